@@ -0,0 +1,16 @@
+#![no_main]
+
+use ectfy::metadata::Metadata;
+use libfuzzer_sys::fuzz_target;
+
+// The first byte picks the format version, the rest is the metadata.
+fuzz_target!(|data: &[u8]| {
+    let Some((&version, metadata_bytes)) = data.split_first() else {
+        return;
+    };
+
+    if let Ok(metadata) = Metadata::deserialize(version, metadata_bytes) {
+        let serialized = metadata.serialize().expect("parsed metadata serializes");
+        Metadata::deserialize(ectfy::metadata::VERSION, &serialized).expect("serialized metadata parses again");
+    }
+});
@@ -1,50 +1,744 @@
-use std::io::Cursor;
-use std::path::Path;
-use tar::{Builder, Archive};
-use walkdir::WalkDir;
+use clap::ValueEnum;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tar::{Builder, Archive, EntryType, Header};
+use walkdir::DirEntry;
 
-pub fn create_tar_archive(folder_path: &Path) -> Result<Vec<u8>, String> {
+use crate::attributes::MODE_BITS;
+use crate::filter::FolderFilter;
+
+/// What to do with FIFOs, sockets and device nodes found in a folder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SpecialFilePolicy {
+    /// Abort encryption
+    #[default]
+    Error,
+    /// Leave them out of the archive
+    Skip,
+    /// Store FIFOs as FIFOs; sockets and device nodes are left out
+    Store,
+}
+
+/// Which extended metadata is stored in folder archives and restored from
+/// them. Numeric uid/gid are always stored in the tar headers; `ownership`
+/// only controls whether they are restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedAttributes {
+    /// Extended attributes such as `user.*` tags and SELinux labels.
+    pub xattrs: bool,
+    /// POSIX ACLs (`system.posix_acl_access` and `system.posix_acl_default`).
+    pub acls: bool,
+    /// Restore numeric uid/gid; only takes effect when running as root.
+    pub ownership: bool,
+}
+
+impl Default for ExtendedAttributes {
+    fn default() -> Self {
+        Self { xattrs: true, acls: true, ownership: true }
+    }
+}
+
+impl ExtendedAttributes {
+    pub fn none() -> Self {
+        Self { xattrs: false, acls: false, ownership: false }
+    }
+
+    fn includes(&self, name: &str) -> bool {
+        if POSIX_ACL_XATTRS.contains(&name) { self.acls } else { self.xattrs }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveOptions {
+    /// Archive what symlinks point to instead of the links themselves.
+    pub follow_symlinks: bool,
+    pub special_files: SpecialFilePolicy,
+    pub extended: ExtendedAttributes,
+    /// Leave out entries that cannot be read instead of failing.
+    pub ignore_unreadable: bool,
+    pub filter: FolderFilter,
+}
+
+/// What [`create_tar_archive`] left out of an archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveReport {
+    /// Entries skipped because they could not be read.
+    pub unreadable: Vec<UnreadableEntry>,
+    /// Number of entries left out by the filter.
+    pub excluded: usize,
+}
+
+impl ArchiveReport {
+    /// Whether the archive holds everything in the folder.
+    pub fn is_complete(&self) -> bool {
+        self.unreadable.is_empty() && self.excluded == 0
+    }
+}
+
+/// PAX keys under which the filter set is recorded.
+const PAX_FILTERS: &str = "ECTFY.filters";
+const PAX_EXCLUDED: &str = "ECTFY.excluded";
+
+/// A folder entry that could not be read while walking it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnreadableEntry {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl UnreadableEntry {
+    pub fn from_walk_error(error: &walkdir::Error, root: &Path) -> Self {
+        Self {
+            path: error.path().unwrap_or(root).to_path_buf(),
+            reason: error.io_error().map(|e| e.to_string()).unwrap_or_else(|| error.to_string()),
+        }
+    }
+}
+
+pub fn format_unreadable(unreadable: &[UnreadableEntry]) -> String {
+    unreadable.iter()
+        .map(|entry| format!("{}: {}", entry.path.display(), entry.reason))
+        .collect::<Vec<_>>()
+        .join("\n  ")
+}
+
+/// Fails if anything could not be read, unless told to carry on without it.
+pub fn check_unreadable(unreadable: &[UnreadableEntry], ignore_unreadable: bool) -> Result<(), String> {
+    if unreadable.is_empty() || ignore_unreadable {
+        return Ok(());
+    }
+
+    Err(format!(
+        "Could not read {} entr{}, nothing was encrypted (use --ignore-unreadable to skip {}):\n  {}",
+        unreadable.len(),
+        if unreadable.len() == 1 { "y" } else { "ies" },
+        if unreadable.len() == 1 { "it" } else { "them" },
+        format_unreadable(unreadable)
+    ))
+}
+
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Linux keeps POSIX ACLs in these extended attributes.
+const POSIX_ACL_XATTRS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+/// Reads the extended attributes selected by `options` as PAX records.
+#[cfg(unix)]
+fn xattr_records(path: &Path, follow_symlinks: bool, options: &ExtendedAttributes) -> Result<Vec<(String, Vec<u8>)>, String> {
+    if !options.xattrs && !options.acls {
+        return Ok(Vec::new());
+    }
+
+    let names = if follow_symlinks { xattr::list_deref(path) } else { xattr::list(path) };
+    let names = match names {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to list extended attributes of {}: {}", path.display(), e)),
+    };
+
+    let mut records = Vec::new();
+    for name in names {
+        let name_str = name.to_str()
+            .ok_or_else(|| format!("{} has an extended attribute whose name is not UTF-8: {:?}", path.display(), name))?;
+        if !options.includes(name_str) {
+            continue;
+        }
+
+        let value = if follow_symlinks { xattr::get_deref(path, &name) } else { xattr::get(path, &name) };
+        let value = value
+            .map_err(|e| format!("Failed to read extended attribute {} of {}: {}", name_str, path.display(), e))?;
+        if let Some(value) = value {
+            records.push((format!("{}{}", PAX_XATTR_PREFIX, name_str), value));
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(not(unix))]
+fn xattr_records(_path: &Path, _follow_symlinks: bool, _options: &ExtendedAttributes) -> Result<Vec<(String, Vec<u8>)>, String> {
+    Ok(Vec::new())
+}
+
+/// Restores the extended attributes recorded for an extracted entry. Like
+/// ownership this is best effort: attributes the filesystem does not support
+/// or the user may not set are left out.
+#[cfg(unix)]
+fn restore_xattrs<R: io::Read>(entry: &mut tar::Entry<'_, R>, target: &Path, options: &ExtendedAttributes) -> Result<(), String> {
+    let Some(extensions) = entry.pax_extensions()
+        .map_err(|e| format!("Failed to read PAX headers: {}", e))? else {
+        return Ok(());
+    };
+
+    for extension in extensions {
+        let extension = extension.map_err(|e| format!("Invalid PAX header: {}", e))?;
+        let Some(name) = extension.key().ok().and_then(|key| key.strip_prefix(PAX_XATTR_PREFIX)) else {
+            continue;
+        };
+        if !options.includes(name) {
+            continue;
+        }
+
+        match xattr::set(target, name, extension.value_bytes()) {
+            Err(e) if !matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported) => {
+                return Err(format!("Failed to set extended attribute {} on {}: {}", name, target.display(), e));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restore_xattrs<R: io::Read>(_entry: &mut tar::Entry<'_, R>, _target: &Path, _options: &ExtendedAttributes) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// Identifies a file with several hard links so later links can refer back
+/// to the first path archived.
+#[cfg(unix)]
+fn hard_link_key(entry: &DirEntry) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = entry.metadata().ok()?;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn hard_link_key(_entry: &DirEntry) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+fn is_fifo(entry: &DirEntry) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    entry.file_type().is_fifo()
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_entry: &DirEntry) -> bool {
+    false
+}
+
+/// A header for an entry with `metadata`, without setuid and setgid bits:
+/// extraction refuses them, so archiving them would make the folder
+/// impossible to restore.
+fn archived_header(metadata: &fs::Metadata) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_metadata(metadata);
+    header.set_mode(header.mode().unwrap_or(0) & MODE_BITS);
+    header
+}
+
+fn append_special<W: io::Write>(
+    builder: &mut Builder<W>,
+    entry: &DirEntry,
+    relative_path: &Path,
+    policy: SpecialFilePolicy,
+) -> Result<(), String> {
+    let fifo = is_fifo(entry);
+    let kind = if fifo { "a FIFO" } else { "a socket or device node" };
+
+    match policy {
+        SpecialFilePolicy::Error => Err(format!(
+            "{} is {}; use --special-files skip or store to archive the folder anyway",
+            entry.path().display(),
+            kind
+        )),
+        SpecialFilePolicy::Store if fifo => {
+            let metadata = entry.metadata()
+                .map_err(|e| format!("Failed to read {}: {}", entry.path().display(), e))?;
+            let mut header = archived_header(&metadata);
+            header.set_entry_type(EntryType::Fifo);
+            header.set_size(0);
+            builder.append_data(&mut header, relative_path, io::empty())
+                .map_err(|e| format!("Failed to append FIFO to tar: {}", e))
+        }
+        SpecialFilePolicy::Skip | SpecialFilePolicy::Store => Ok(()),
+    }
+}
+
+/// Writes a PAX header carrying the extended attributes of `path`; it
+/// applies to the entry appended right after it.
+fn append_xattr_records<W: io::Write>(builder: &mut Builder<W>, path: &Path, options: &ArchiveOptions) -> Result<(), String> {
+    let records = xattr_records(path, options.follow_symlinks, &options.extended)?;
+    builder.append_pax_extensions(records.iter().map(|(key, value)| (key.as_str(), value.as_slice())))
+        .map_err(|e| format!("Failed to append extended attributes to tar: {}", e))
+}
+
+/// Encodes PAX `length key=value` records.
+fn pax_records<'a>(records: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (key, value) in records {
+        // The length includes its own digits, the space, `=` and newline.
+        let rest = key.len() + value.len() + 3;
+        let mut digits = 1;
+        while (rest + digits).to_string().len() > digits {
+            digits += 1;
+        }
+        data.extend_from_slice(format!("{} {}=", rest + digits, key).as_bytes());
+        data.extend_from_slice(value);
+        data.push(b'\n');
+    }
+    data
+}
+
+/// Records the filter set in a PAX global header so the archive says what
+/// was left out.
+fn append_filter_record<W: io::Write>(builder: &mut Builder<W>, filter: &FolderFilter, excluded: usize) -> Result<(), String> {
+    let filters = filter.describe().join("\n");
+    let excluded = excluded.to_string();
+    let data = pax_records([(PAX_FILTERS, filters.as_bytes()), (PAX_EXCLUDED, excluded.as_bytes())]);
+
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::XGlobalHeader);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    builder.append_data(&mut header, "pax_global_header", data.as_slice())
+        .map_err(|e| format!("Failed to record filters in tar: {}", e))
+}
+
+/// Archives `folder_path`. Entries that could not be read abort the archive
+/// unless `ignore_unreadable` is set; they are reported along with how much
+/// the filter left out.
+pub fn create_tar_archive(folder_path: &Path, options: &ArchiveOptions) -> Result<(Vec<u8>, ArchiveReport), String> {
     let mut buffer = Vec::new();
+    let mut unreadable = Vec::new();
+    let excluded;
     {
         let mut builder = Builder::new(&mut buffer);
+        builder.follow_symlinks(options.follow_symlinks);
+        let mut hard_links: HashMap<(u64, u64), PathBuf> = HashMap::new();
 
-        for entry in WalkDir::new(folder_path)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
+        let mut walker = options.filter.walker(folder_path, options.follow_symlinks)?;
+
+        for entry in walker.entries() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    unreadable.push(UnreadableEntry::from_walk_error(&e, folder_path));
+                    continue;
+                }
+            };
             let path = entry.path();
             let file_type = entry.file_type();
+            let relative_path = path.strip_prefix(folder_path)
+                .map_err(|e| format!("Failed to get relative path: {}", e))?;
+
+            if relative_path == Path::new("") {
+                continue;
+            }
 
-            if file_type.is_file() {
-                let relative_path = path.strip_prefix(folder_path)
-                    .map_err(|e| format!("Failed to get relative path: {}", e))?;
-                
-                builder.append_path_with_name(path, relative_path)
-                    .map_err(|e| format!("Failed to append file to tar: {}", e))?;
-            } else if file_type.is_dir() {
-                let relative_path = path.strip_prefix(folder_path)
-                    .map_err(|e| format!("Failed to get relative path: {}", e))?;
-                
-                if relative_path != Path::new("") {
-                    builder.append_path_with_name(path, relative_path)
-                        .map_err(|e| format!("Failed to append directory to tar: {}", e))?;
+            if file_type.is_dir() {
+                let metadata = entry.metadata()
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                let mut header = archived_header(&metadata);
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                append_xattr_records(&mut builder, path, options)?;
+                builder.append_data(&mut header, relative_path, io::empty())
+                    .map_err(|e| format!("Failed to append directory to tar: {}", e))?;
+            } else if file_type.is_symlink() {
+                let metadata = entry.metadata()
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                let target = fs::read_link(path)
+                    .map_err(|e| format!("Failed to read symlink {}: {}", path.display(), e))?;
+                let mut header = archived_header(&metadata);
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, relative_path, &target)
+                    .map_err(|e| format!("Failed to append symlink to tar: {}", e))?;
+            } else if file_type.is_file() {
+                let link_key = hard_link_key(&entry);
+                let first_link = link_key.and_then(|key| hard_links.get(&key).cloned());
+
+                if let Some(first_link) = first_link {
+                    let metadata = entry.metadata()
+                        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                    let mut header = archived_header(&metadata);
+                    header.set_entry_type(EntryType::Link);
+                    header.set_size(0);
+                    builder.append_link(&mut header, relative_path, &first_link)
+                        .map_err(|e| format!("Failed to append hard link to tar: {}", e))?;
+                } else {
+                    let mut file = match fs::File::open(path) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            unreadable.push(UnreadableEntry { path: path.to_path_buf(), reason: e.to_string() });
+                            continue;
+                        }
+                    };
+                    if let Some(key) = link_key {
+                        hard_links.insert(key, relative_path.to_path_buf());
+                    }
+                    let metadata = file.metadata()
+                        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                    let mut header = archived_header(&metadata);
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(metadata.len());
+                    append_xattr_records(&mut builder, path, options)?;
+                    builder.append_data(&mut header, relative_path, &mut file)
+                        .map_err(|e| format!("Failed to append file to tar: {}", e))?;
                 }
+            } else {
+                append_special(&mut builder, &entry, relative_path, options.special_files)?;
             }
         }
 
+        excluded = walker.excluded();
+        if !options.filter.is_empty() {
+            append_filter_record(&mut builder, &options.filter, excluded)?;
+        }
+
         builder.finish()
             .map_err(|e| format!("Failed to finish tar archive: {}", e))?;
     }
 
-    Ok(buffer)
+    check_unreadable(&unreadable, options.ignore_unreadable)?;
+
+    // The source is deleted after encryption, so never produce an archive
+    // that extraction would refuse.
+    let rejected = check_tar_entries(buffer.as_slice())?;
+    if !rejected.is_empty() {
+        let hint = if rejected.iter().any(|entry| entry.symlink) { " (try --follow-symlinks)" } else { "" };
+        return Err(format!(
+            "The folder contains entries that could not be restored safely{}:\n  {}",
+            hint,
+            format_rejected(&rejected)
+        ));
+    }
+
+    Ok((buffer, ArchiveReport { unreadable, excluded }))
+}
+
+/// An archive entry the extractor refused to write.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedEntry {
+    pub path: String,
+    pub reason: String,
+    /// Whether a symlink in the archive is the reason.
+    pub symlink: bool,
+}
+
+const SETUID_SETGID: u32 = 0o6000;
+
+/// Splits a path into its normal components, or `None` if it is absolute or
+/// climbs with `..`.
+fn contained_components(path: &Path) -> Option<Vec<OsString>> {
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => components.push(part.to_os_string()),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(components)
+}
+
+fn symlink_ancestor<'a>(components: &[OsString], symlinks: &'a HashSet<Vec<OsString>>) -> Option<&'a Vec<OsString>> {
+    (1..components.len()).find_map(|len| symlinks.get(&components[..len]))
+}
+
+fn check_symlink_target(entry_components: &[OsString], target: &Path, symlinks: &HashSet<Vec<OsString>>) -> Result<(), String> {
+    if target.has_root() {
+        return Err("symlink points to an absolute path".to_string());
+    }
+
+    let mut resolved = entry_components[..entry_components.len() - 1].to_vec();
+    let parts: Vec<Component> = target.components().collect();
+    for (index, component) in parts.iter().enumerate() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if resolved.pop().is_none() {
+                    return Err("symlink points outside the archive".to_string());
+                }
+            }
+            Component::Normal(part) => {
+                resolved.push(part.to_os_string());
+                if index + 1 < parts.len() && symlinks.contains(&resolved) {
+                    return Err("symlink points through another symlink".to_string());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err("symlink points to an absolute path".to_string());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks every entry before anything is written. Rejects absolute paths and
+/// `..`, paths through archived symlinks, symlinks and hard links leading
+/// outside the archive, device nodes and setuid/setgid bits. Reads the
+/// archive as a stream, so it can check one that is still being decrypted.
+pub fn check_tar_entries<R: io::Read>(reader: R) -> Result<Vec<RejectedEntry>, String> {
+    struct Scanned {
+        path: String,
+        components: Option<Vec<OsString>>,
+        entry_type: EntryType,
+        link_name: Option<PathBuf>,
+        mode: u32,
+    }
+
+    let mut archive = Archive::new(reader);
+    let mut scanned = Vec::new();
+
+    for entry in archive.entries().map_err(|e| format!("Failed to read tar archive: {}", e))? {
+        let entry = entry.map_err(|e| format!("Failed to read tar entry: {}", e))?;
+        if entry.header().entry_type().is_pax_global_extensions() {
+            continue;
+        }
+        let path = entry.path()
+            .map_err(|e| format!("Invalid tar entry path: {}", e))?;
+
+        scanned.push(Scanned {
+            path: path.display().to_string(),
+            components: contained_components(&path),
+            entry_type: entry.header().entry_type(),
+            link_name: entry.link_name()
+                .map_err(|e| format!("Invalid link name for {}: {}", path.display(), e))?
+                .map(|name| name.into_owned()),
+            mode: entry.header().mode().unwrap_or(0),
+        });
+    }
+
+    let symlinks: HashSet<Vec<OsString>> = scanned.iter()
+        .filter(|entry| entry.entry_type.is_symlink())
+        .filter_map(|entry| entry.components.clone())
+        .collect();
+
+    let mut rejected = Vec::new();
+    for entry in &scanned {
+        let reject = |reason: String| RejectedEntry { path: entry.path.clone(), reason, symlink: false };
+        let reject_symlink = |reason: String| RejectedEntry { symlink: true, ..reject(reason) };
+
+        let Some(components) = &entry.components else {
+            rejected.push(reject("path is absolute or contains `..`".to_string()));
+            continue;
+        };
+        if components.is_empty() {
+            rejected.push(reject("path is empty".to_string()));
+            continue;
+        }
+        if let Some(symlink) = symlink_ancestor(components, &symlinks) {
+            let symlink: PathBuf = symlink.iter().collect();
+            rejected.push(reject_symlink(format!("path goes through symlink {}", symlink.display())));
+            continue;
+        }
+
+        let entry_type = entry.entry_type;
+        if entry_type.is_block_special() || entry_type.is_character_special() {
+            rejected.push(reject("device nodes are not extracted".to_string()));
+            continue;
+        }
+
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let Some(target) = entry.link_name.as_deref().filter(|t| !t.as_os_str().is_empty()) else {
+                rejected.push(reject("link has no target".to_string()));
+                continue;
+            };
+
+            let result = if entry_type.is_symlink() {
+                check_symlink_target(components, target, &symlinks)
+            } else {
+                match contained_components(target) {
+                    Some(target) if !target.is_empty() && symlink_ancestor(&target, &symlinks).is_none() => Ok(()),
+                    _ => Err("hard link points outside the archive".to_string()),
+                }
+            };
+            if let Err(reason) = result {
+                rejected.push(if entry_type.is_symlink() { reject_symlink(reason) } else { reject(reason) });
+                continue;
+            }
+        } else if !(entry_type.is_file() || entry_type.is_dir() || entry_type.is_fifo() || entry_type.is_contiguous()) {
+            rejected.push(reject(format!("unsupported entry type {:?}", entry_type)));
+            continue;
+        }
+
+        if entry.mode & SETUID_SETGID != 0 {
+            rejected.push(reject("setuid/setgid bits are not extracted".to_string()));
+        }
+    }
+
+    Ok(rejected)
+}
+
+/// What kind of entry a listed archive entry is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListedKind {
+    Directory,
+    File,
+    Symlink(PathBuf),
+    HardLink(PathBuf),
+    Fifo,
+    Other,
+}
+
+/// One entry of a folder archive, as `ls` shows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedEntry {
+    pub path: PathBuf,
+    pub kind: ListedKind,
+    pub size: u64,
 }
 
-pub fn extract_tar_archive(archive_data: &[u8], extract_to: &Path) -> Result<(), String> {
-    let cursor = Cursor::new(archive_data);
-    let mut archive = Archive::new(cursor);
-    
-    archive.unpack(extract_to)
-        .map_err(|e| format!("Failed to extract tar archive: {}", e))?;
+/// Lists the entries of a tar stream in archive order without extracting
+/// anything. File contents are skipped as the stream is read.
+pub fn list_tar_entries<R: io::Read>(reader: R) -> Result<Vec<ListedEntry>, String> {
+    let mut archive = Archive::new(reader);
+    let mut listed = Vec::new();
+
+    for entry in archive.entries().map_err(|e| format!("Failed to read tar archive: {}", e))? {
+        let entry = entry.map_err(|e| format!("Failed to read tar entry: {}", e))?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_pax_global_extensions() {
+            continue;
+        }
+        let path = entry.path()
+            .map_err(|e| format!("Invalid tar entry path: {}", e))?
+            .into_owned();
+        let link_name = || -> Result<PathBuf, String> {
+            Ok(entry.link_name()
+                .map_err(|e| format!("Invalid link name for {}: {}", path.display(), e))?
+                .map(|name| name.into_owned())
+                .unwrap_or_default())
+        };
+
+        let kind = if entry_type.is_dir() {
+            ListedKind::Directory
+        } else if entry_type.is_file() || entry_type.is_contiguous() {
+            ListedKind::File
+        } else if entry_type.is_symlink() {
+            ListedKind::Symlink(link_name()?)
+        } else if entry_type.is_hard_link() {
+            ListedKind::HardLink(link_name()?)
+        } else if entry_type.is_fifo() {
+            ListedKind::Fifo
+        } else {
+            ListedKind::Other
+        };
+
+        listed.push(ListedEntry { size: entry.size(), path, kind });
+    }
+
+    Ok(listed)
+}
+
+pub(crate) fn format_rejected(rejected: &[RejectedEntry]) -> String {
+    rejected.iter()
+        .map(|entry| format!("{}: {}", entry.path, entry.reason))
+        .collect::<Vec<_>>()
+        .join("\n  ")
+}
+
+/// Refuses the whole archive if [`check_tar_entries`] rejected any entry.
+pub fn refuse_rejected(rejected: &[RejectedEntry]) -> Result<(), String> {
+    if rejected.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "Refusing to extract {} unsafe entr{}:\n  {}",
+        rejected.len(),
+        if rejected.len() == 1 { "y" } else { "ies" },
+        format_rejected(rejected)
+    ))
+}
+
+#[cfg(unix)]
+fn make_fifo(path: &Path, mode: u32) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `c_path` is a valid NUL-terminated string that outlives the call.
+    if unsafe { libc::mkfifo(c_path.as_ptr(), (mode & 0o777) as libc::mode_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_fifo(path: &Path, _mode: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("cannot create FIFO {}", path.display())))
+}
+
+/// tar writes FIFO entries as empty regular files, so they are created here.
+/// The path has already passed [`check_tar_entries`].
+fn unpack_fifo<R: io::Read>(entry: &tar::Entry<'_, R>, extract_to: &Path) -> Result<(), String> {
+    let relative_path = entry.path()
+        .map_err(|e| format!("Invalid tar entry path: {}", e))?;
+    let target = extract_to.join(&relative_path);
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+
+    make_fifo(&target, entry.header().mode().unwrap_or(0o644))
+        .map_err(|e| format!("Failed to create FIFO {}: {}", relative_path.display(), e))
+}
+
+/// Extracts into `extract_to`, refusing the whole archive if any entry fails
+/// [`check_tar_entries`].
+pub fn extract_tar_archive(archive_data: &[u8], extract_to: &Path, restore: &ExtendedAttributes) -> Result<(), String> {
+    refuse_rejected(&check_tar_entries(archive_data)?)?;
+    unpack_checked_tar(archive_data, extract_to, restore)
+}
+
+/// Extracts a tar stream into `extract_to` as it is read. The same stream
+/// must already have passed [`check_tar_entries`] without rejections.
+pub fn unpack_checked_tar<R: io::Read>(reader: R, extract_to: &Path, restore: &ExtendedAttributes) -> Result<(), String> {
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_ownerships(restore.ownership && is_root());
+    let mut directories = Vec::new();
+
+    for entry in archive.entries().map_err(|e| format!("Failed to read tar archive: {}", e))? {
+        let mut entry = entry.map_err(|e| format!("Failed to read tar entry: {}", e))?;
+        let entry_type = entry.header().entry_type();
+
+        // Directories go last so their permissions cannot block their contents.
+        if entry_type.is_dir() {
+            directories.push(entry);
+            continue;
+        }
+
+        if entry_type.is_fifo() {
+            unpack_fifo(&entry, extract_to)?;
+            continue;
+        }
+
+        entry.unpack_in(extract_to)
+            .map_err(|e| format!("Failed to extract tar entry: {}", e))?;
+        if entry_type.is_file() {
+            let target = extract_to.join(entry.path().map_err(|e| format!("Invalid tar entry path: {}", e))?);
+            restore_xattrs(&mut entry, &target, restore)?;
+        }
+    }
+
+    for mut directory in directories {
+        directory.unpack_in(extract_to)
+            .map_err(|e| format!("Failed to extract tar entry: {}", e))?;
+        let target = extract_to.join(directory.path().map_err(|e| format!("Invalid tar entry path: {}", e))?);
+        restore_xattrs(&mut directory, &target, restore)?;
+    }
 
     Ok(())
 }
@@ -69,12 +763,12 @@ mod tests {
         fs::write(&file1, b"File 1 content").unwrap();
         fs::write(&file2, b"File 2 content").unwrap();
 
-        let archive_data = create_tar_archive(&test_folder).unwrap();
+        let (archive_data, _) = create_tar_archive(&test_folder, &ArchiveOptions::default()).unwrap();
         assert!(!archive_data.is_empty());
 
         let extract_dir = temp_dir.path().join("extracted");
         fs::create_dir(&extract_dir).unwrap();
-        extract_tar_archive(&archive_data, &extract_dir).unwrap();
+        extract_tar_archive(&archive_data, &extract_dir, &ExtendedAttributes::default()).unwrap();
 
         let extracted_file1 = extract_dir.join("file1.txt");
         let extracted_file2 = extract_dir.join("subdir").join("file2.txt");
@@ -86,13 +780,212 @@ mod tests {
         assert_eq!(fs::read(&extracted_file2).unwrap(), b"File 2 content");
     }
 
+    fn raw_entry(builder: &mut Builder<&mut Vec<u8>>, path: &str, entry_type: EntryType, link: Option<&str>, mode: u32) {
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        if let Some(link) = link {
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        }
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(0);
+        header.set_cksum();
+        builder.append(&header, std::io::empty()).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_unsafe_entries() {
+        let mut buffer = Vec::new();
+        {
+            let mut builder = Builder::new(&mut buffer);
+            raw_entry(&mut builder, "ok.txt", EntryType::Regular, None, 0o644);
+            raw_entry(&mut builder, "/etc/passwd", EntryType::Regular, None, 0o644);
+            raw_entry(&mut builder, "../../.bashrc", EntryType::Regular, None, 0o644);
+            raw_entry(&mut builder, "escape", EntryType::Symlink, Some("../outside"), 0o777);
+            raw_entry(&mut builder, "absolute", EntryType::Symlink, Some("/etc"), 0o777);
+            raw_entry(&mut builder, "inside", EntryType::Symlink, Some("ok.txt"), 0o777);
+            raw_entry(&mut builder, "absolute/passwd", EntryType::Regular, None, 0o644);
+            raw_entry(&mut builder, "hard", EntryType::Link, Some("../outside"), 0o644);
+            raw_entry(&mut builder, "tty", EntryType::Char, None, 0o644);
+            raw_entry(&mut builder, "suid", EntryType::Regular, None, 0o4755);
+            builder.finish().unwrap();
+        }
+
+        let rejected: Vec<String> = check_tar_entries(buffer.as_slice()).unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(rejected, ["/etc/passwd", "../../.bashrc", "escape", "absolute", "absolute/passwd", "hard", "tty", "suid"]);
+
+        let temp_dir = TempDir::new().unwrap();
+        let error = extract_tar_archive(&buffer, temp_dir.path(), &ExtendedAttributes::default()).unwrap_err();
+        assert!(error.contains("8 unsafe entries"), "{}", error);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0, "Nothing should be extracted");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_links_and_fifos_round_trip() {
+        use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt};
+
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir_all(folder.join("subdir")).unwrap();
+        fs::write(folder.join("data.txt"), b"shared").unwrap();
+        fs::hard_link(folder.join("data.txt"), folder.join("subdir").join("link.txt")).unwrap();
+        symlink("../data.txt", folder.join("subdir").join("symlink.txt")).unwrap();
+        make_fifo(&folder.join("pipe"), 0o600).unwrap();
+
+        assert!(create_tar_archive(&folder, &ArchiveOptions::default()).is_err(), "FIFOs should be refused by default");
+
+        let options = ArchiveOptions { special_files: SpecialFilePolicy::Store, ..ArchiveOptions::default() };
+        let (archive_data, _) = create_tar_archive(&folder, &options).unwrap();
+
+        let mut listed = list_tar_entries(archive_data.as_slice()).unwrap();
+        listed.sort_by(|a, b| a.path.cmp(&b.path));
+        // Whichever of the hard-linked names is archived first holds the data.
+        let (stored, linked) = match &listed[0].kind {
+            ListedKind::File => (0, 3),
+            _ => (3, 0),
+        };
+        assert_eq!(listed[stored].size, 6);
+        assert_eq!(listed[linked].kind, ListedKind::HardLink(listed[stored].path.clone()));
+        assert_eq!(listed[1], ListedEntry { path: PathBuf::from("pipe"), kind: ListedKind::Fifo, size: 0 });
+        assert_eq!(listed[2].kind, ListedKind::Directory);
+        assert_eq!(listed[4].kind, ListedKind::Symlink(PathBuf::from("../data.txt")));
+
+        let extract_dir = temp_dir.path().join("extracted");
+        fs::create_dir(&extract_dir).unwrap();
+        extract_tar_archive(&archive_data, &extract_dir, &ExtendedAttributes::default()).unwrap();
+
+        let symlink_path = extract_dir.join("subdir").join("symlink.txt");
+        assert_eq!(fs::read_link(&symlink_path).unwrap(), Path::new("../data.txt"));
+        assert_eq!(fs::read(&symlink_path).unwrap(), b"shared");
+        let original = fs::metadata(extract_dir.join("data.txt")).unwrap();
+        let linked = fs::metadata(extract_dir.join("subdir").join("link.txt")).unwrap();
+        assert_eq!(original.ino(), linked.ino(), "Hard links should be restored as hard links");
+        assert!(fs::symlink_metadata(extract_dir.join("pipe")).unwrap().file_type().is_fifo());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_symlinks() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir(&folder).unwrap();
+        fs::write(temp_dir.path().join("outside.txt"), b"outside").unwrap();
+        std::os::unix::fs::symlink(temp_dir.path().join("outside.txt"), folder.join("link.txt")).unwrap();
+
+        let error = create_tar_archive(&folder, &ArchiveOptions::default()).unwrap_err();
+        assert!(error.contains("absolute path"), "{}", error);
+        assert!(error.contains("--follow-symlinks"), "{}", error);
+
+        let options = ArchiveOptions { follow_symlinks: true, ..ArchiveOptions::default() };
+        let (archive_data, _) = create_tar_archive(&folder, &options).unwrap();
+
+        let extract_dir = temp_dir.path().join("extracted");
+        fs::create_dir(&extract_dir).unwrap();
+        extract_tar_archive(&archive_data, &extract_dir, &ExtendedAttributes::default()).unwrap();
+        let extracted = extract_dir.join("link.txt");
+        assert!(!fs::symlink_metadata(&extracted).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&extracted).unwrap(), b"outside");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_setgid_directory_round_trip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir_all(folder.join("shared")).unwrap();
+        fs::set_permissions(folder.join("shared"), fs::Permissions::from_mode(0o2775)).unwrap();
+        fs::write(folder.join("shared").join("tool"), b"tool").unwrap();
+        fs::set_permissions(folder.join("shared").join("tool"), fs::Permissions::from_mode(0o4755)).unwrap();
+
+        let (archive_data, _) = create_tar_archive(&folder, &ArchiveOptions::default()).unwrap();
+        let mut archive = Archive::new(archive_data.as_slice());
+        let modes: HashMap<PathBuf, u32> = archive.entries().unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.path().unwrap().into_owned(), entry.header().mode().unwrap())
+            })
+            .collect();
+        assert_eq!(modes[Path::new("shared")] & 0o7777, 0o775);
+        assert_eq!(modes[Path::new("shared/tool")] & 0o7777, 0o755);
+
+        let extract_dir = temp_dir.path().join("extracted");
+        fs::create_dir(&extract_dir).unwrap();
+        extract_tar_archive(&archive_data, &extract_dir, &ExtendedAttributes::default()).unwrap();
+        assert_eq!(fs::read(extract_dir.join("shared").join("tool")).unwrap(), b"tool");
+        for path in ["shared", "shared/tool"] {
+            let mode = fs::metadata(extract_dir.join(path)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o6000, 0, "{} should not be setuid or setgid", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_xattrs_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir(&folder).unwrap();
+        fs::write(folder.join("tagged.txt"), b"tagged").unwrap();
+        if xattr::set(folder.join("tagged.txt"), "user.ectfy.tag", b"blue").is_err() {
+            // The temp filesystem does not support user xattrs.
+            return;
+        }
+        xattr::set(&folder, "user.ectfy.tag", b"folder").unwrap();
+
+        let (archive_data, _) = create_tar_archive(&folder, &ArchiveOptions::default()).unwrap();
+
+        let extract_dir = temp_dir.path().join("extracted");
+        fs::create_dir(&extract_dir).unwrap();
+        extract_tar_archive(&archive_data, &extract_dir, &ExtendedAttributes::default()).unwrap();
+        assert_eq!(xattr::get(extract_dir.join("tagged.txt"), "user.ectfy.tag").unwrap(), Some(b"blue".to_vec()));
+
+        let plain_dir = temp_dir.path().join("plain");
+        fs::create_dir(&plain_dir).unwrap();
+        extract_tar_archive(&archive_data, &plain_dir, &ExtendedAttributes::none()).unwrap();
+        assert_eq!(xattr::get(plain_dir.join("tagged.txt"), "user.ectfy.tag").unwrap(), None);
+
+        let options = ArchiveOptions { extended: ExtendedAttributes::none(), ..ArchiveOptions::default() };
+        let (archive_data, _) = create_tar_archive(&folder, &options).unwrap();
+        assert!(!archive_data.windows(b"user.ectfy.tag".len()).any(|window| window == b"user.ectfy.tag"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir(&folder).unwrap();
+        fs::write(folder.join("kept.txt"), b"kept").unwrap();
+        std::os::unix::fs::symlink("missing.txt", folder.join("dangling")).unwrap();
+
+        let options = ArchiveOptions { follow_symlinks: true, ..ArchiveOptions::default() };
+        let error = create_tar_archive(&folder, &options).unwrap_err();
+        assert!(error.contains("--ignore-unreadable") && error.contains("dangling"), "{}", error);
+
+        let options = ArchiveOptions { ignore_unreadable: true, ..options };
+        let (archive_data, report) = create_tar_archive(&folder, &options).unwrap();
+        assert_eq!(report.unreadable.len(), 1);
+        assert_eq!(report.unreadable[0].path, folder.join("dangling"));
+
+        let extract_dir = temp_dir.path().join("extracted");
+        fs::create_dir(&extract_dir).unwrap();
+        extract_tar_archive(&archive_data, &extract_dir, &ExtendedAttributes::default()).unwrap();
+        assert_eq!(fs::read(extract_dir.join("kept.txt")).unwrap(), b"kept");
+        assert!(fs::symlink_metadata(extract_dir.join("dangling")).is_err());
+    }
+
     #[test]
     fn test_create_tar_empty_folder() {
         let temp_dir = TempDir::new().unwrap();
         let empty_folder = temp_dir.path().join("empty_folder");
         fs::create_dir(&empty_folder).unwrap();
 
-        let archive_data = create_tar_archive(&empty_folder).unwrap();
+        let (archive_data, _) = create_tar_archive(&empty_folder, &ArchiveOptions::default()).unwrap();
         assert!(!archive_data.is_empty());
     }
 }
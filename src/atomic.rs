@@ -0,0 +1,268 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn temp_path_for(path: &Path) -> Result<PathBuf, String> {
+    let name = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("Invalid output path: {}", path.display()))?;
+
+    Ok(parent_dir(path).join(format!(".{}.{:08x}.tmp", name, rand::random::<u32>())))
+}
+
+/// Creates the directory `path` goes into, for outputs nested under an
+/// output directory that were planned before anything was written.
+fn create_parent_dir(path: &Path) -> Result<(), String> {
+    let parent = parent_dir(path);
+    fs::create_dir_all(parent)
+        .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))
+}
+
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn sync_tree(root: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(root).contents_first(true) {
+        let entry = entry.map_err(io::Error::from)?;
+        let file_type = entry.file_type();
+        if file_type.is_file() {
+            fs::File::open(entry.path())?.sync_all()?;
+        } else if file_type.is_dir() {
+            sync_dir(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_any(path: &Path) -> io::Result<()> {
+    if path.symlink_metadata()?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Renames a finished temp file or folder to `path`. Whatever is at `path` is
+/// only replaced when `replace` is set; a folder in the way is moved aside
+/// first and put back if the final rename fails.
+fn move_into_place(temp_path: &Path, path: &Path, replace: bool) -> Result<(), String> {
+    let existing = match path.symlink_metadata() {
+        Ok(existing) => existing,
+        Err(_) => {
+            return fs::rename(temp_path, path)
+                .map_err(|e| format!("Failed to move output into place: {}", e));
+        }
+    };
+
+    if !replace {
+        return Err(format!("Output already exists: {}", path.display()));
+    }
+
+    if !existing.is_dir() && !temp_path.is_dir() {
+        return fs::rename(temp_path, path)
+            .map_err(|e| format!("Failed to move output into place: {}", e));
+    }
+
+    let aside_path = temp_path_for(path)?;
+    fs::rename(path, &aside_path)
+        .map_err(|e| format!("Failed to move {} aside: {}", path.display(), e))?;
+
+    if let Err(e) = fs::rename(temp_path, path) {
+        let _ = fs::rename(&aside_path, path);
+        return Err(format!("Failed to move output into place: {}", e));
+    }
+
+    remove_any(&aside_path)
+        .map_err(|e| format!("Failed to remove replaced {}: {}", path.display(), e))
+}
+
+/// A file written and fsynced under a temp name next to its destination,
+/// waiting to be moved into place. Dropping it without
+/// [`StagedFile::commit`] removes the temp file and leaves the destination
+/// untouched.
+pub struct StagedFile {
+    temp_path: PathBuf,
+    path: PathBuf,
+    replace: bool,
+    committed: bool,
+}
+
+impl StagedFile {
+    /// Where the staged contents can be read back before they are committed.
+    pub fn temp_path(&self) -> &Path {
+        &self.temp_path
+    }
+
+    /// Renames the temp file over the destination and fsyncs the directory.
+    pub fn commit(mut self) -> Result<(), String> {
+        move_into_place(&self.temp_path, &self.path, self.replace)?;
+        self.committed = true;
+        sync_dir(parent_dir(&self.path))
+            .map_err(|e| format!("Failed to sync directory: {}", e))
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Fills a temp file in the same directory as `path` and fsyncs it, without
+/// moving it into place yet. On failure the temp file is removed.
+pub fn stage_file<F>(path: &Path, replace: bool, write: F) -> Result<StagedFile, String>
+where
+    F: FnOnce(&mut fs::File) -> io::Result<()>,
+{
+    create_parent_dir(path)?;
+    let temp_path = temp_path_for(path)?;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .map_err(|e| format!("Failed to create temp file: {}", e))?;
+    let staged = StagedFile { temp_path, path: path.to_path_buf(), replace, committed: false };
+
+    write(&mut file)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    file.sync_all()
+        .map_err(|e| format!("Failed to sync {}: {}", path.display(), e))?;
+    Ok(staged)
+}
+
+/// Writes a file by filling a temp file in the same directory, fsyncing it,
+/// renaming it over `path` and fsyncing the directory. On failure the temp
+/// file is removed and `path` is left untouched.
+pub fn write_file_atomic<F>(path: &Path, replace: bool, write: F) -> Result<(), String>
+where
+    F: FnOnce(&mut fs::File) -> io::Result<()>,
+{
+    stage_file(path, replace, write)?.commit()
+}
+
+/// Builds a directory under a temp name next to `path`, fsyncs everything in
+/// it and renames it into place. Unless `replace` is set, `path` must not
+/// exist yet.
+pub fn create_dir_atomic<F>(path: &Path, replace: bool, fill: F) -> Result<(), String>
+where
+    F: FnOnce(&Path) -> Result<(), String>,
+{
+    if !replace && path.symlink_metadata().is_ok() {
+        return Err(format!("Output already exists: {}", path.display()));
+    }
+
+    create_parent_dir(path)?;
+    let temp_path = temp_path_for(path)?;
+
+    let result = (|| {
+        fs::create_dir(&temp_path)
+            .map_err(|e| format!("Failed to create temp directory: {}", e))?;
+
+        fill(&temp_path)?;
+
+        sync_tree(&temp_path)
+            .map_err(|e| format!("Failed to sync {}: {}", path.display(), e))?;
+        move_into_place(&temp_path, path, replace)?;
+        sync_dir(parent_dir(path))
+            .map_err(|e| format!("Failed to sync directory: {}", e))
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_file_atomic_replaces_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("out.txt");
+        fs::write(&target, b"old").unwrap();
+
+        assert!(write_file_atomic(&target, false, |file| file.write_all(b"new")).is_err());
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+
+        write_file_atomic(&target, true, |file| file.write_all(b"new")).unwrap();
+
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1, "Temp file should not be left behind");
+    }
+
+    #[test]
+    fn test_write_file_atomic_failure_keeps_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("out.txt");
+        fs::write(&target, b"old").unwrap();
+
+        let result = write_file_atomic(&target, true, |file| {
+            file.write_all(b"partial")?;
+            Err(io::Error::other("simulated failure"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1, "Temp file should be cleaned up");
+    }
+
+    #[test]
+    fn test_dropped_staged_file_keeps_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("out.txt");
+        fs::write(&target, b"old").unwrap();
+
+        let staged = stage_file(&target, true, |file| file.write_all(b"new")).unwrap();
+        assert_eq!(fs::read(staged.temp_path()).unwrap(), b"new");
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        drop(staged);
+
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1, "Temp file should be removed");
+    }
+
+    #[test]
+    fn test_create_dir_atomic() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("folder");
+
+        create_dir_atomic(&target, false, |dir| {
+            fs::write(dir.join("file.txt"), b"content")
+                .map_err(|e| e.to_string())
+        }).unwrap();
+
+        assert_eq!(fs::read(target.join("file.txt")).unwrap(), b"content");
+        assert!(create_dir_atomic(&target, false, |_| Ok(())).is_err(), "Existing target should be refused");
+
+        create_dir_atomic(&target, true, |dir| {
+            fs::write(dir.join("other.txt"), b"replaced")
+                .map_err(|e| e.to_string())
+        }).unwrap();
+
+        assert!(!target.join("file.txt").exists(), "Replaced folder should not be merged");
+        assert_eq!(fs::read(target.join("other.txt")).unwrap(), b"replaced");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1, "Moved-aside folder should be removed");
+    }
+}
@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A point in time as seconds and nanoseconds relative to the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    pub fn from_system_time(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => Self { secs: after.as_secs() as i64, nanos: after.subsec_nanos() },
+            Err(e) => {
+                let before = e.duration();
+                if before.subsec_nanos() == 0 {
+                    Self { secs: -(before.as_secs() as i64), nanos: 0 }
+                } else {
+                    Self { secs: -(before.as_secs() as i64) - 1, nanos: 1_000_000_000 - before.subsec_nanos() }
+                }
+            }
+        }
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        if self.secs >= 0 {
+            UNIX_EPOCH + Duration::new(self.secs as u64, self.nanos)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(self.secs.unsigned_abs()) + Duration::from_nanos(self.nanos as u64)
+        }
+    }
+}
+
+/// The mode bits kept and restored: permissions and the sticky bit, but never
+/// setuid or setgid, which a decrypted file should not gain.
+pub(crate) const MODE_BITS: u32 = 0o1777;
+
+/// Permissions, timestamps and ownership of a single encrypted file. They
+/// travel inside the encrypted payload, never in the plaintext header.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileAttributes {
+    pub mode: Option<u32>,
+    pub modified: Option<Timestamp>,
+    pub accessed: Option<Timestamp>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FileAttributes {
+    pub fn capture(metadata: &fs::Metadata) -> Self {
+        let mut attributes = Self {
+            modified: metadata.modified().ok().map(Timestamp::from_system_time),
+            accessed: metadata.accessed().ok().map(Timestamp::from_system_time),
+            ..Self::default()
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            attributes.mode = Some(metadata.mode() & MODE_BITS);
+            attributes.uid = Some(metadata.uid());
+            attributes.gid = Some(metadata.gid());
+        }
+
+        attributes
+    }
+
+    /// Restores the attributes on an open file. Ownership is best effort:
+    /// without the privilege to chown, the file keeps the current user.
+    pub fn apply(&self, file: &fs::File) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{fchown, MetadataExt, PermissionsExt};
+
+            let current = file.metadata()?;
+            let uid = self.uid.filter(|uid| *uid != current.uid());
+            let gid = self.gid.filter(|gid| *gid != current.gid());
+            if uid.is_some() || gid.is_some() {
+                match fchown(file, uid, gid) {
+                    Err(e) if e.kind() != io::ErrorKind::PermissionDenied => return Err(e),
+                    _ => {}
+                }
+            }
+
+            if let Some(mode) = self.mode {
+                file.set_permissions(fs::Permissions::from_mode(mode & MODE_BITS))?;
+            }
+        }
+
+        let mut times = fs::FileTimes::new();
+        if let Some(modified) = self.modified {
+            times = times.set_modified(modified.to_system_time());
+        }
+        if let Some(accessed) = self.accessed {
+            times = times.set_accessed(accessed.to_system_time());
+        }
+        file.set_times(times)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_timestamp_round_trip() {
+        for time in [
+            UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+            UNIX_EPOCH - Duration::new(86_400, 250_000_000),
+            UNIX_EPOCH,
+        ] {
+            assert_eq!(Timestamp::from_system_time(time).to_system_time(), time);
+        }
+    }
+
+    #[test]
+    fn test_capture_and_apply() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+        fs::write(&source, b"source").unwrap();
+        fs::write(&target, b"target").unwrap();
+
+        let source_file = fs::File::options().write(true).open(&source).unwrap();
+        source_file.set_times(fs::FileTimes::new()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+            .set_accessed(UNIX_EPOCH + Duration::from_secs(1_100_000_000)))
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            source_file.set_permissions(fs::Permissions::from_mode(0o750)).unwrap();
+        }
+
+        let attributes = FileAttributes::capture(&fs::metadata(&source).unwrap());
+        attributes.apply(&fs::File::options().write(true).open(&target).unwrap()).unwrap();
+
+        let restored = fs::metadata(&target).unwrap();
+        assert_eq!(restored.modified().unwrap(), UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(restored.permissions().mode() & 0o7777, 0o750);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_setuid_and_setgid_are_not_restored() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        fs::write(&source, b"source").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o4755)).unwrap();
+        assert_eq!(FileAttributes::capture(&fs::metadata(&source).unwrap()).mode, Some(0o755));
+
+        // A payload could still carry the bits, so applying drops them too.
+        let target = temp_dir.path().join("target");
+        fs::write(&target, b"target").unwrap();
+        let attributes = FileAttributes { mode: Some(0o6755), ..FileAttributes::default() };
+        attributes.apply(&fs::File::options().write(true).open(&target).unwrap()).unwrap();
+        assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o7777, 0o755);
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::conflict::ConflictPolicy;
+use crate::archive::{check_unreadable, format_unreadable, ArchiveOptions};
+use crate::encryption::generate_salt;
+use crate::file_ops::{collect_files_recursive, encrypted_name, has_encrypted_extension, is_encrypted_file, read_header, DecryptOptions, EncryptOptions, OutputOptions};
+
+/// Whether the inputs of a batch are encrypted or decrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Decrypt inputs that start with an encrypted header, encrypt the rest.
+    #[default]
+    Auto,
+    /// Encrypt every input, even one that is encrypted already.
+    Encrypt,
+    /// Decrypt every input. Folders processed file by file only contribute
+    /// their encrypted files.
+    Decrypt,
+}
+
+impl Mode {
+    fn decrypts(self, path: &Path) -> bool {
+        match self {
+            Mode::Auto => is_encrypted_file(path),
+            Mode::Encrypt => false,
+            Mode::Decrypt => true,
+        }
+    }
+}
+
+/// One input of a batch and where its output goes.
+#[derive(Debug, Clone)]
+pub struct BatchItem {
+    pub input: PathBuf,
+    pub output: OutputOptions,
+    /// Decrypt the input rather than encrypt it.
+    pub decrypt: bool,
+}
+
+impl BatchItem {
+    fn new(input: PathBuf, output: OutputOptions, mode: Mode) -> Self {
+        let decrypt = mode.decrypts(&input);
+        Self { input, output, decrypt }
+    }
+
+    /// `options` as they apply to this item, run with `jobs` threads.
+    pub fn encrypt_options(&self, options: &EncryptOptions, jobs: usize) -> EncryptOptions {
+        EncryptOptions { output: self.output.clone(), jobs, ..options.clone() }
+    }
+
+    /// `options` as they apply to this item, run with `jobs` threads.
+    pub fn decrypt_options(&self, options: &DecryptOptions, jobs: usize) -> DecryptOptions {
+        DecryptOptions { output: self.output.clone(), jobs, ..options.clone() }
+    }
+
+    /// Explains what is done with an input whose name and contents disagree
+    /// about whether it is encrypted.
+    pub fn mismatch_warning(&self) -> Option<String> {
+        let extension = has_encrypted_extension(&self.input);
+        let path = self.input.display();
+        if self.decrypt && !extension {
+            Some(format!("{} is encrypted but does not end in .ect; decrypting it", path))
+        } else if !self.decrypt && is_encrypted_file(&self.input) {
+            Some(format!("{} is already encrypted; encrypting it again", path))
+        } else if !self.decrypt && extension && self.input.is_file() {
+            Some(format!("{} ends in .ect but is not encrypted; encrypting it", path))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedOutput {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+/// Makes paths comparable by canonicalizing the parent directory, so that
+/// `./a.ect` and `dir/../a.ect` count as the same output.
+fn normalize(path: &Path) -> PathBuf {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.to_path_buf();
+    };
+    let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+
+    fs::canonicalize(parent)
+        .map(|parent| parent.join(name))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf, String> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf, String> {
+    String::from_utf8(bytes.to_vec())
+        .map(PathBuf::from)
+        .map_err(|_| format!("Path in list is not valid UTF-8: {:?}", String::from_utf8_lossy(bytes)))
+}
+
+/// Splits a list of paths. A list containing a NUL byte is NUL-separated
+/// (as written by `find -print0`); otherwise it has one path per line.
+/// Empty entries are ignored.
+pub fn parse_path_list(data: &[u8]) -> Result<Vec<PathBuf>, String> {
+    let entries: Vec<&[u8]> = if data.contains(&0) {
+        data.split(|byte| *byte == 0).collect()
+    } else {
+        data.split(|byte| *byte == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .collect()
+    };
+
+    entries.into_iter()
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+/// Reads a list of paths from `source`, or from stdin if it is `-`.
+pub fn read_path_list(source: &Path) -> Result<Vec<PathBuf>, String> {
+    let data = if source == Path::new("-") {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data)
+            .map_err(|e| format!("Failed to read file list from stdin: {}", e))?;
+        data
+    } else {
+        fs::read(source)
+            .map_err(|e| format!("Failed to read file list {}: {}", source.display(), e))?
+    };
+
+    parse_path_list(&data)
+}
+
+/// Turns the paths given on the command line into batch items, deciding
+/// for each whether it is decrypted by `mode`. With `per_file`, a folder
+/// stands for the files under it that pass the archive filters, each written
+/// to the matching place under `--out-dir`; otherwise it is archived as a
+/// whole.
+pub fn expand_inputs(
+    paths: &[PathBuf],
+    output: &OutputOptions,
+    per_file: bool,
+    archive: &ArchiveOptions,
+    mode: Mode,
+) -> Result<Vec<BatchItem>, String> {
+    let mut items = Vec::new();
+
+    for path in paths {
+        if !(per_file && path.is_dir()) {
+            if mode == Mode::Decrypt && path.is_dir() {
+                return Err(format!("{} is a folder; only encrypted files can be decrypted", path.display()));
+            }
+            items.push(BatchItem::new(path.clone(), output.clone(), mode));
+            continue;
+        }
+
+        let (files, unreadable) = collect_files_recursive(path, archive)?;
+        check_unreadable(&unreadable, archive.ignore_unreadable)?;
+        for file in files {
+            if mode == Mode::Decrypt && !is_encrypted_file(&file) {
+                continue;
+            }
+            let output = output.for_nested(path, &file)?;
+            items.push(BatchItem::new(file, output, mode));
+        }
+    }
+
+    Ok(items)
+}
+
+/// Turns paths into the encrypted files they stand for: files as given, and
+/// the encrypted files anywhere under folders.
+pub fn expand_encrypted_inputs(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut inputs = Vec::new();
+
+    for path in paths {
+        if !path.is_dir() {
+            inputs.push(path.clone());
+            continue;
+        }
+
+        let (files, unreadable) = collect_files_recursive(path, &ArchiveOptions::default())?;
+        if !unreadable.is_empty() {
+            return Err(format!("Could not read all of {}:\n  {}", path.display(), format_unreadable(&unreadable)));
+        }
+        inputs.extend(files.into_iter().filter(|file| is_encrypted_file(file)));
+    }
+
+    Ok(inputs)
+}
+
+/// The encryption options of a batch. Several encrypted outputs share one
+/// master key, so PBKDF2 runs once per batch.
+pub fn batch_encrypt_options(items: &[BatchItem], options: &EncryptOptions) -> EncryptOptions {
+    let mut options = options.clone();
+    if items.iter().filter(|item| !item.decrypt).count() > 1 {
+        options.master_salt = Some(generate_salt());
+    }
+    options
+}
+
+/// Works out where each input of a batch will be written. Encrypted inputs
+/// whose header cannot be read are left out; they fail on their own later.
+pub fn plan_outputs(items: &[BatchItem]) -> Result<Vec<PlannedOutput>, String> {
+    let mut plan = Vec::new();
+
+    for BatchItem { input, output, decrypt } in items {
+        let output = if *decrypt {
+            let Ok(metadata) = read_header(input) else {
+                continue;
+            };
+            output.target_path(input, &metadata.original_name)?
+        } else {
+            output.target_path(input, &encrypted_name(input)?)?
+        };
+
+        plan.push(PlannedOutput { input: input.clone(), output });
+    }
+
+    Ok(plan)
+}
+
+/// Checks a whole batch before anything is touched: two inputs must not
+/// write the same output, no output may clobber another input, and with the
+/// `error` policy no output may already exist.
+pub fn check_collisions(plan: &[PlannedOutput], policy: ConflictPolicy) -> Result<(), String> {
+    let inputs: HashMap<PathBuf, &Path> = plan.iter()
+        .map(|planned| (normalize(&planned.input), planned.input.as_path()))
+        .collect();
+    let mut outputs: HashMap<PathBuf, &Path> = HashMap::new();
+    let mut problems = Vec::new();
+
+    for planned in plan {
+        let output = normalize(&planned.output);
+
+        if let Some(other_input) = inputs.get(&output) {
+            problems.push(format!(
+                "{} would be written to {}, which is also an input",
+                planned.input.display(),
+                other_input.display()
+            ));
+        }
+
+        if let Some(first_input) = outputs.insert(output, &planned.input) {
+            if matches!(policy, ConflictPolicy::Error | ConflictPolicy::Overwrite) {
+                problems.push(format!(
+                    "{} and {} would both be written to {}",
+                    first_input.display(),
+                    planned.input.display(),
+                    planned.output.display()
+                ));
+            }
+        }
+
+        if policy == ConflictPolicy::Error && planned.output.symlink_metadata().is_ok() {
+            problems.push(format!("{} already exists", planned.output.display()));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Outputs collide, nothing was changed:\n  {}", problems.join("\n  ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::Password;
+    use crate::file_ops::{decrypt_file, encrypt_file};
+    use crate::metadata::{golden, KeyDerivation, VERSION};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_path_list() {
+        assert_eq!(
+            parse_path_list(b"a.txt\r\nsub dir/b.txt\n\n").unwrap(),
+            vec![PathBuf::from("a.txt"), PathBuf::from("sub dir/b.txt")]
+        );
+        assert_eq!(
+            parse_path_list(b"line\nbreak.txt\0c.txt\0").unwrap(),
+            vec![PathBuf::from("line\nbreak.txt"), PathBuf::from("c.txt")]
+        );
+        assert!(parse_path_list(b"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_plan_appends_extension() {
+        let temp_dir = TempDir::new().unwrap();
+        let pdf = temp_dir.path().join("report.pdf");
+        let docx = temp_dir.path().join("report.docx");
+        fs::write(&pdf, b"pdf").unwrap();
+        fs::write(&docx, b"docx").unwrap();
+
+        let items = expand_inputs(&[pdf.clone(), docx.clone()], &OutputOptions::default(), false, &ArchiveOptions::default(), Mode::Auto).unwrap();
+        let plan = plan_outputs(&items).unwrap();
+
+        assert_eq!(plan[0].output, temp_dir.path().join("report.pdf.ect"));
+        assert_eq!(plan[1].output, temp_dir.path().join("report.docx.ect"));
+        assert!(check_collisions(&plan, ConflictPolicy::Error).is_ok());
+    }
+
+    #[test]
+    fn test_detects_collisions_across_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let out_dir = temp_dir.path().join("out");
+        fs::create_dir_all(temp_dir.path().join("a")).unwrap();
+        fs::create_dir_all(temp_dir.path().join("b")).unwrap();
+        fs::create_dir(&out_dir).unwrap();
+        let first = temp_dir.path().join("a").join("notes.txt");
+        let second = temp_dir.path().join("b").join("notes.txt");
+        fs::write(&first, b"a").unwrap();
+        fs::write(&second, b"b").unwrap();
+
+        let output = OutputOptions { dir: Some(out_dir.clone()), ..OutputOptions::default() };
+        let items = expand_inputs(&[first, second], &output, false, &ArchiveOptions::default(), Mode::Auto).unwrap();
+        let plan = plan_outputs(&items).unwrap();
+
+        let error = check_collisions(&plan, ConflictPolicy::Error).unwrap_err();
+        assert!(error.contains("would both be written to"), "{}", error);
+        assert!(check_collisions(&plan, ConflictPolicy::Rename).is_ok());
+
+        fs::write(out_dir.join("notes.txt.ect"), b"existing").unwrap();
+        let error = check_collisions(&plan, ConflictPolicy::Error).unwrap_err();
+        assert!(error.contains("already exists"), "{}", error);
+    }
+
+    #[test]
+    fn test_expand_inputs_per_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("docs");
+        let out_dir = temp_dir.path().join("out");
+        fs::create_dir_all(folder.join("sub")).unwrap();
+        fs::write(folder.join("a.txt"), b"a").unwrap();
+        fs::write(folder.join("sub").join("b.txt"), b"b").unwrap();
+
+        let output = OutputOptions { dir: Some(out_dir.clone()), ..OutputOptions::default() };
+        let archived = expand_inputs(std::slice::from_ref(&folder), &output, false, &ArchiveOptions::default(), Mode::Auto).unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].input, folder);
+
+        let items = expand_inputs(std::slice::from_ref(&folder), &output, true, &ArchiveOptions::default(), Mode::Auto).unwrap();
+        let mut plan = plan_outputs(&items).unwrap();
+        plan.sort_by(|a, b| a.input.cmp(&b.input));
+        assert_eq!(plan[0].output, out_dir.join("a.txt.ect"));
+        assert_eq!(plan[1].output, out_dir.join("sub").join("b.txt.ect"));
+        check_collisions(&plan, ConflictPolicy::Error).unwrap();
+        assert!(!out_dir.exists(), "Planning should not create output directories");
+
+        // The nested directory is created when its first output is written.
+        let item = items.iter().find(|item| item.input.ends_with("b.txt")).unwrap();
+        let options = EncryptOptions { keep: true, ..item.encrypt_options(&EncryptOptions::default(), 1) };
+        encrypt_file(&item.input, &Password::from("password"), "Question?", &options).unwrap();
+        assert!(out_dir.join("sub").join("b.txt.ect").is_file());
+    }
+
+    #[test]
+    fn test_per_file_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("docs");
+        fs::create_dir_all(folder.join("sub")).unwrap();
+        let file1 = folder.join("file1.txt");
+        let file2 = folder.join("sub").join("file2.txt");
+        fs::write(&file1, b"File 1 content").unwrap();
+        fs::write(&file2, b"File 2 content").unwrap();
+
+        let password = Password::from("test_password");
+        let paths = [folder.clone()];
+        let output = OutputOptions::default();
+        let archive = ArchiveOptions::default();
+
+        let items = expand_inputs(&paths, &output, true, &archive, Mode::Auto).unwrap();
+        assert_eq!(items.len(), 2);
+        assert!(items.iter().all(|item| !item.decrypt));
+        check_collisions(&plan_outputs(&items).unwrap(), ConflictPolicy::Error).unwrap();
+
+        let options = batch_encrypt_options(&items, &EncryptOptions::default());
+        let encrypted: Vec<PathBuf> = items.iter()
+            .map(|item| encrypt_file(&item.input, &password, "Test question", &item.encrypt_options(&options, 1)).unwrap().into_path())
+            .collect();
+        assert!(folder.join("file1.txt.ect").exists());
+        assert!(!file1.exists(), "Original file1 should be deleted after encryption");
+        assert!(!file2.exists(), "Original file2 should be deleted after encryption");
+
+        // One master salt for the batch, a separate subkey for every file.
+        let first = read_header(&encrypted[0]).unwrap();
+        let second = read_header(&encrypted[1]).unwrap();
+        assert_eq!(first.salt, second.salt);
+        assert!(matches!(first.key_derivation, KeyDerivation::Pbkdf2Hkdf { .. }));
+        assert_ne!(first.key_derivation, second.key_derivation);
+
+        // Plain files are left alone whatever they are called; a corrupt
+        // encrypted file fails on its own.
+        fs::write(folder.join("plain.ect"), b"not encrypted").unwrap();
+        fs::write(folder.join("broken.ect"), b"ECTF\x02\xff\xff\xff\xffcorrupt").unwrap();
+        let items = expand_inputs(&paths, &output, true, &archive, Mode::Decrypt).unwrap();
+        assert_eq!(items.len(), 3);
+        let results: Vec<(PathBuf, bool)> = items.iter()
+            .map(|item| (item.input.clone(), decrypt_file(&item.input, &password, &item.decrypt_options(&DecryptOptions::default(), 1)).is_ok()))
+            .collect();
+        assert_eq!(results.iter().filter(|(_, ok)| *ok).count(), 2);
+        assert!(results.iter().any(|(file, ok)| file.ends_with("broken.ect") && !ok));
+        assert_eq!(fs::read(&file2).unwrap(), b"File 2 content");
+        for encrypted_file in &encrypted {
+            assert!(!encrypted_file.exists(), "Encrypted files should be deleted after decryption");
+        }
+    }
+
+    #[test]
+    fn test_detects_encrypted_files_by_contents() {
+        let temp_dir = TempDir::new().unwrap();
+        let renamed = temp_dir.path().join("report.pdf");
+        fs::copy(golden(VERSION, "hello.txt.ect"), &renamed).unwrap();
+        let misnamed = temp_dir.path().join("notes.ect");
+        fs::write(&misnamed, b"plain notes").unwrap();
+        let paths = [renamed.clone(), misnamed.clone()];
+        let archive = ArchiveOptions::default();
+
+        let items = expand_inputs(&paths, &OutputOptions::default(), false, &archive, Mode::Auto).unwrap();
+        assert!(items[0].decrypt);
+        assert!(items[0].mismatch_warning().unwrap().contains("does not end in .ect"));
+        assert!(!items[1].decrypt);
+        assert!(items[1].mismatch_warning().unwrap().contains("is not encrypted"));
+
+        let plan = plan_outputs(&items).unwrap();
+        assert_eq!(plan[0].output, temp_dir.path().join("hello.txt"));
+        assert_eq!(plan[1].output, temp_dir.path().join("notes.ect.ect"));
+
+        let items = expand_inputs(&paths, &OutputOptions::default(), false, &archive, Mode::Encrypt).unwrap();
+        assert!(items.iter().all(|item| !item.decrypt));
+        assert!(items[0].mismatch_warning().unwrap().contains("already encrypted"));
+
+        let folder = std::slice::from_ref(&temp_dir.path().to_path_buf()).to_vec();
+        let items = expand_inputs(&folder, &OutputOptions::default(), true, &archive, Mode::Decrypt).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].input, renamed);
+        assert!(expand_inputs(&folder, &OutputOptions::default(), false, &archive, Mode::Decrypt).is_err());
+    }
+}
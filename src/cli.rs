@@ -1,21 +1,309 @@
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, FromArgMatches, Id, Parser, Subcommand};
+use std::ffi::OsString;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::archive::SpecialFilePolicy;
+use crate::conflict::ConflictPolicy;
+use crate::encryption::{DEFAULT_PBKDF2_ITERATIONS, MAX_PBKDF2_ITERATIONS};
+use crate::filter::{parse_size, parse_time};
 
 #[derive(Parser, Debug)]
 #[command(name = "ectfy")]
 #[command(about = "Encrypt and decrypt files using AES-256-GCM")]
 #[command(version)]
 pub struct Cli {
-    #[arg(help = "Path to file or folder to encrypt/decrypt")]
-    pub path: Option<PathBuf>,
-    
-    #[arg(short = 's', long = "show-password", help = "Show password as it's being entered")]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub batch: BatchArgs,
+
+    #[arg(long = "encrypt", conflicts_with = "decrypt", help = "Encrypt every input, even ones that are already encrypted")]
+    pub encrypt: bool,
+
+    #[arg(long = "decrypt", help = "Decrypt every input, whatever it is called")]
+    pub decrypt: bool,
+
+    #[command(flatten)]
+    pub encrypting: EncryptArgs,
+
+    #[command(flatten)]
+    pub decrypting: DecryptArgs,
+
+    #[command(flatten)]
+    pub extended: ExtendedArgs,
+
+    #[arg(short = 's', long = "show-password", global = true, help = "Show password as it's being entered")]
     pub show_password: bool,
+
+    #[arg(short = 'j', long = "jobs", value_name = "N", global = true, help = "Process up to N files at once [default: number of CPUs]")]
+    pub jobs: Option<NonZeroUsize>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[command(about = "Encrypt files and folders, even ones that are already encrypted")]
+    Encrypt {
+        #[command(flatten)]
+        batch: BatchArgs,
+
+        #[command(flatten)]
+        encrypting: EncryptArgs,
+
+        #[command(flatten)]
+        extended: ExtendedArgs,
+    },
+
+    #[command(about = "Decrypt encrypted files, whatever they are called")]
+    Decrypt {
+        #[command(flatten)]
+        batch: BatchArgs,
+
+        #[command(flatten)]
+        decrypting: DecryptArgs,
+
+        #[command(flatten)]
+        extended: ExtendedArgs,
+    },
+
+    #[command(about = "Show what the header of an encrypted file says, without asking for the password")]
+    Info {
+        #[arg(required = true, help = "Encrypted files")]
+        paths: Vec<PathBuf>,
+
+        #[arg(long = "json", help = "Print a JSON array with one object per file")]
+        json: bool,
+    },
+
+    #[command(about = "Check that encrypted files decrypt with their password, without writing anything")]
+    Verify {
+        #[arg(required = true, help = "Encrypted files, or folders to search for encrypted files")]
+        paths: Vec<PathBuf>,
+    },
+
+    #[command(about = "List the contents of encrypted files and folder archives without writing them")]
+    Ls {
+        #[arg(required = true, help = "Encrypted files, or folders to search for encrypted files")]
+        paths: Vec<PathBuf>,
+    },
+
+    #[command(about = "Change the password and helper question of encrypted files")]
+    Rekey {
+        #[arg(required = true, help = "Encrypted files, or folders to search for encrypted files")]
+        paths: Vec<PathBuf>,
+
+        #[command(flatten)]
+        rewrite: RewriteArgs,
+    },
+
+    #[command(about = "Rewrite encrypted files from older versions in the current format")]
+    Upgrade {
+        #[arg(required = true, help = "Encrypted files, or folders to search for encrypted files")]
+        paths: Vec<PathBuf>,
+
+        #[command(flatten)]
+        rewrite: RewriteArgs,
+    },
+
+    #[command(about = "Print a random password")]
+    Keygen {
+        #[arg(
+            long = "length",
+            value_name = "N",
+            default_value_t = 32,
+            value_parser = clap::value_parser!(u16).range(16..=1024),
+            help = "Number of characters"
+        )]
+        length: u16,
+    },
+}
+
+/// Which inputs to process and where their outputs go.
+#[derive(Args, Debug, Default)]
+pub struct BatchArgs {
+    #[arg(help = "Paths to files or folders; without any, they are picked interactively")]
+    pub paths: Vec<PathBuf>,
+
+    #[arg(long = "files-from", value_name = "FILE", help = "Read more paths from FILE, one per line or NUL-separated; `-` reads stdin")]
+    pub files_from: Option<PathBuf>,
+
+    #[arg(short = 'o', long = "output", value_name = "PATH", conflicts_with = "out_dir", help = "Write the output to this path (single input only)")]
+    pub output: Option<PathBuf>,
+
+    #[arg(long = "out-dir", value_name = "DIR", help = "Write outputs into this directory instead of next to the inputs")]
+    pub out_dir: Option<PathBuf>,
+
+    #[arg(long = "on-conflict", value_enum, value_name = "POLICY", help = "What to do when an output already exists [default: error]")]
+    pub on_conflict: Option<ConflictPolicy>,
+
+    #[arg(long = "per-file", help = "Process the files in folders one by one instead of archiving the folders")]
+    pub per_file: bool,
 }
 
+/// How files and folders are encrypted.
+#[derive(Args, Debug, Default)]
+pub struct EncryptArgs {
+    #[arg(long = "no-verify", help = "Skip re-reading and authenticating the output before deleting originals")]
+    pub no_verify: bool,
+
+    #[arg(short = 'k', long = "keep", overrides_with = "no_keep", help = "Keep the original after encrypting")]
+    pub keep: bool,
+
+    #[arg(long = "no-keep", overrides_with = "keep", help = "Delete the original after encrypting, even if the config says keep")]
+    pub no_keep: bool,
+
+    #[arg(long = "follow-symlinks", help = "Archive what symlinks in a folder point to instead of the links")]
+    pub follow_symlinks: bool,
+
+    #[arg(long = "special-files", value_enum, value_name = "POLICY", default_value_t = SpecialFilePolicy::Error, help = "What to do with FIFOs, sockets and devices in a folder")]
+    pub special_files: SpecialFilePolicy,
+
+    #[arg(long = "exclude", value_name = "GLOB", help = "Leave matching entries out of folder archives (repeatable)")]
+    pub exclude: Vec<String>,
+
+    #[arg(long = "include", value_name = "GLOB", help = "Only archive files matching one of these globs (repeatable)")]
+    pub include: Vec<String>,
+
+    #[arg(long = "respect-gitignore", help = "Leave out files ignored by .gitignore files in the folder")]
+    pub respect_gitignore: bool,
+
+    #[arg(long = "one-file-system", help = "Don't descend into other filesystems mounted inside the folder")]
+    pub one_file_system: bool,
+
+    #[arg(long = "min-size", value_name = "SIZE", value_parser = parse_size, help = "Only archive files at least this big, e.g. 10K")]
+    pub min_size: Option<u64>,
+
+    #[arg(long = "max-size", value_name = "SIZE", value_parser = parse_size, help = "Only archive files at most this big, e.g. 100M")]
+    pub max_size: Option<u64>,
+
+    #[arg(long = "modified-after", value_name = "DATE", value_parser = parse_time, help = "Only archive files modified at or after this UTC date (YYYY-MM-DD[THH:MM:SS])")]
+    pub modified_after: Option<SystemTime>,
+
+    #[arg(long = "modified-before", value_name = "DATE", value_parser = parse_time, help = "Only archive files modified before this UTC date")]
+    pub modified_before: Option<SystemTime>,
+
+    #[arg(long = "ignore-unreadable", help = "Encrypt a folder even if some entries can't be read; they are reported and the folder is kept")]
+    pub ignore_unreadable: bool,
+}
+
+/// How files and folders are decrypted.
+#[derive(Args, Debug, Default)]
+pub struct DecryptArgs {
+    #[arg(long = "no-owner", help = "Don't restore the uid/gid of folder contents (only done as root)")]
+    pub no_owner: bool,
+
+    #[arg(long = "no-preserve", help = "Don't restore permissions, timestamps and ownership when decrypting")]
+    pub no_preserve: bool,
+
+    #[arg(long = "keep-encrypted", overrides_with = "no_keep_encrypted", help = "Keep the .ect file after decrypting")]
+    pub keep_encrypted: bool,
+
+    #[arg(long = "no-keep-encrypted", overrides_with = "keep_encrypted", help = "Delete the .ect file after decrypting, even if the config says keep")]
+    pub no_keep_encrypted: bool,
+}
+
+/// Extended metadata of folder contents, stored when encrypting and restored
+/// when decrypting.
+#[derive(Args, Debug, Default)]
+pub struct ExtendedArgs {
+    #[arg(long = "no-xattrs", help = "Don't store or restore extended attributes of folder contents")]
+    pub no_xattrs: bool,
+
+    #[arg(long = "no-acls", help = "Don't store or restore POSIX ACLs of folder contents")]
+    pub no_acls: bool,
+}
+
+/// How `upgrade` and `rekey` rewrite encrypted files.
+#[derive(Args, Debug)]
+pub struct RewriteArgs {
+    #[arg(
+        long = "kdf-iterations",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(DEFAULT_PBKDF2_ITERATIONS as i64..=MAX_PBKDF2_ITERATIONS as i64),
+        help = "Derive keys with N PBKDF2 rounds; files that already use more keep theirs [default: 100000]"
+    )]
+    pub kdf_iterations: Option<u32>,
+
+    #[arg(long = "no-verify", help = "Replace files without first checking that the rewritten copy decrypts")]
+    pub no_verify: bool,
+}
+
+/// Options that apply to every subcommand as well as the default mode.
+const GLOBAL_ARGS: &[&str] = &["show_password", "jobs"];
+
 impl Cli {
     pub fn parse_args() -> Self {
-        Self::parse()
+        Self::try_parse_args(std::env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Parses `args` like [`Parser::try_parse_from`], refusing options of the
+    /// default mode in front of a subcommand, where they would do nothing.
+    pub fn try_parse_args<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let mut command = Self::command();
+        let matches = command.try_get_matches_from_mut(args)?;
+
+        if let Some((name, _)) = matches.subcommand() {
+            let misplaced = matches.ids()
+                .map(Id::as_str)
+                .filter(|id| !GLOBAL_ARGS.contains(id))
+                .find(|id| matches.value_source(id) == Some(ValueSource::CommandLine));
+            if let Some(id) = misplaced {
+                let arg = command.get_arguments()
+                    .find(|arg| arg.get_id() == id)
+                    .and_then(|arg| arg.get_long().map(|long| format!("--{}", long)))
+                    .unwrap_or_else(|| id.to_string());
+                return Err(command.error(
+                    ErrorKind::ArgumentConflict,
+                    format!("{} is not an option of `{}`; give it after the subcommand if it takes it", arg, name),
+                ));
+            }
+        }
+
+        Self::from_arg_matches(&matches)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_args(["ectfy", "-k", "report.pdf"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(cli.encrypting.keep);
+        assert_eq!(cli.batch.paths, [PathBuf::from("report.pdf")]);
+
+        let cli = Cli::try_parse_args(["ectfy", "decrypt", "-s", "--keep-encrypted", "backup"]).unwrap();
+        assert!(cli.show_password);
+        assert!(matches!(cli.command, Some(Command::Decrypt { ref batch, ref decrypting, .. }) if decrypting.keep_encrypted && batch.paths == [PathBuf::from("backup")]));
+
+        // Global options go on either side of the subcommand.
+        let cli = Cli::try_parse_args(["ectfy", "-s", "-j", "2", "verify", "a.ect"]).unwrap();
+        assert!(cli.show_password);
+        assert!(matches!(cli.command, Some(Command::Verify { .. })));
+
+        // Flags of one mode are not accepted by another, or before it.
+        assert!(Cli::try_parse_args(["ectfy", "decrypt", "--keep", "a.ect"]).is_err());
+        assert!(Cli::try_parse_args(["ectfy", "encrypt", "--keep-encrypted", "a.txt"]).is_err());
+        let error = Cli::try_parse_args(["ectfy", "--keep", "encrypt", "a.txt"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+        assert!(Cli::try_parse_args(["ectfy", "info"]).is_err());
+
+        // After a path, a subcommand name is another path.
+        let cli = Cli::try_parse_args(["ectfy", "a.txt", "info"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.batch.paths, [PathBuf::from("a.txt"), PathBuf::from("info")]);
+    }
+}
@@ -0,0 +1,101 @@
+use clap::ValueEnum;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::conflict::ConflictPolicy;
+
+/// Defaults read from the config file. Command-line flags take precedence.
+///
+/// The file is a list of `key = value` lines; `#` starts a comment.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub keep: bool,
+    pub keep_encrypted: bool,
+    pub on_conflict: Option<ConflictPolicy>,
+}
+
+/// `$ECTFY_CONFIG`, else `$XDG_CONFIG_HOME/ectfy/config`, else `~/.config/ectfy/config`.
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("ECTFY_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_dir.join("ectfy").join("config"))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("Invalid value for {}: expected true or false, got {:?}", key, value)),
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        let Some(path) = config_path() else {
+            return Ok(Self::default());
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&contents)
+                .map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Failed to read config file {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line.split_once('=')
+                .ok_or_else(|| format!("line {}: expected `key = value`", index + 1))?;
+            let (key, value) = (key.trim(), value.trim());
+
+            match key {
+                "keep" => config.keep = parse_bool(key, value)?,
+                "keep_encrypted" => config.keep_encrypted = parse_bool(key, value)?,
+                "on_conflict" => {
+                    let policy = ConflictPolicy::from_str(value, true)
+                        .map_err(|_| format!("Invalid value for {}: {:?}", key, value))?;
+                    config.on_conflict = Some(policy);
+                }
+                _ => return Err(format!("line {}: unknown setting {:?}", index + 1, key)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse("# defaults\nkeep = true\n\nkeep_encrypted = no # peek only\non_conflict = rename\n").unwrap();
+        assert_eq!(config, Config { keep: true, keep_encrypted: false, on_conflict: Some(ConflictPolicy::Rename) });
+    }
+
+    #[test]
+    fn test_parse_config_rejects_unknown_keys() {
+        assert!(Config::parse("keep_everything = true").is_err());
+        assert!(Config::parse("keep = maybe").is_err());
+        assert!(Config::parse("keep").is_err());
+        assert!(Config::parse("on_conflict = clobber").is_err());
+    }
+}
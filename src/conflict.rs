@@ -0,0 +1,143 @@
+use clap::ValueEnum;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::password::read_line;
+
+/// What to do when an output path is already taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Refuse and report an error
+    #[default]
+    Error,
+    /// Replace the existing file or folder
+    Overwrite,
+    /// Pick a free name such as `name (1).txt`
+    Rename,
+    /// Leave the existing output alone and skip the input
+    Skip,
+    /// Ask what to do for each conflict
+    Ask,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// Nothing is in the way; write to this path.
+    Create(PathBuf),
+    /// Write to this path, replacing what is there.
+    Replace(PathBuf),
+    /// Do not write; this path is already taken.
+    Skip(PathBuf),
+}
+
+fn exists(path: &Path) -> bool {
+    path.symlink_metadata().is_ok()
+}
+
+/// Finds the first free `stem (n).ext` next to `path`. Folders get the
+/// suffix on the whole name.
+pub fn free_name(path: &Path, is_dir: bool) -> Result<PathBuf, String> {
+    let name = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("Invalid output path: {}", path.display()))?;
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !is_dir && !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+
+    for n in 1..10_000 {
+        let candidate_name = match extension {
+            Some(extension) => format!("{} ({}).{}", stem, n, extension),
+            None => format!("{} ({})", stem, n),
+        };
+        let candidate = path.with_file_name(candidate_name);
+        if !exists(&candidate) {
+            return Ok(candidate);
+        }
+    }
+
+    Err(format!("Could not find a free name for {}", path.display()))
+}
+
+fn prompt_policy(path: &Path) -> Result<ConflictPolicy, String> {
+    loop {
+        print!("{} already exists. [o]verwrite, [r]ename, [s]kip or [a]bort? ", path.display());
+        io::stdout().flush()
+            .map_err(|e| format!("Failed to write prompt: {}", e))?;
+
+        let mut answer = String::new();
+        let read = read_line(&mut answer)
+            .map_err(|e| format!("Failed to read answer: {}", e))?;
+        if read == 0 {
+            return Ok(ConflictPolicy::Error);
+        }
+
+        match answer.trim().to_lowercase().as_str() {
+            "o" | "overwrite" => return Ok(ConflictPolicy::Overwrite),
+            "r" | "rename" => return Ok(ConflictPolicy::Rename),
+            "s" | "skip" => return Ok(ConflictPolicy::Skip),
+            "a" | "abort" => return Ok(ConflictPolicy::Error),
+            _ => println!("Please answer o, r, s or a."),
+        }
+    }
+}
+
+/// Applies `policy` to a planned output path.
+pub fn resolve_conflict(path: &Path, is_dir: bool, policy: ConflictPolicy) -> Result<Resolution, String> {
+    if !exists(path) {
+        return Ok(Resolution::Create(path.to_path_buf()));
+    }
+
+    let policy = match policy {
+        ConflictPolicy::Ask => prompt_policy(path)?,
+        policy => policy,
+    };
+
+    match policy {
+        ConflictPolicy::Error | ConflictPolicy::Ask => {
+            Err(format!("Output already exists: {}", path.display()))
+        }
+        ConflictPolicy::Overwrite => Ok(Resolution::Replace(path.to_path_buf())),
+        ConflictPolicy::Rename => Ok(Resolution::Create(free_name(path, is_dir)?)),
+        ConflictPolicy::Skip => Ok(Resolution::Skip(path.to_path_buf())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_resolve_conflict_policies() {
+        let temp_dir = TempDir::new().unwrap();
+        let free = temp_dir.path().join("free.txt");
+        let taken = temp_dir.path().join("taken.txt");
+        fs::write(&taken, b"existing").unwrap();
+
+        for policy in [ConflictPolicy::Error, ConflictPolicy::Overwrite, ConflictPolicy::Skip] {
+            assert_eq!(resolve_conflict(&free, false, policy).unwrap(), Resolution::Create(free.clone()));
+        }
+
+        assert!(resolve_conflict(&taken, false, ConflictPolicy::Error).is_err());
+        assert_eq!(resolve_conflict(&taken, false, ConflictPolicy::Overwrite).unwrap(), Resolution::Replace(taken.clone()));
+        assert_eq!(resolve_conflict(&taken, false, ConflictPolicy::Skip).unwrap(), Resolution::Skip(taken.clone()));
+        assert_eq!(
+            resolve_conflict(&taken, false, ConflictPolicy::Rename).unwrap(),
+            Resolution::Create(temp_dir.path().join("taken (1).txt"))
+        );
+    }
+
+    #[test]
+    fn test_free_name() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("photo.jpg.ect");
+        fs::write(&file, b"").unwrap();
+        fs::write(temp_dir.path().join("photo.jpg (1).ect"), b"").unwrap();
+
+        assert_eq!(free_name(&file, false).unwrap(), temp_dir.path().join("photo.jpg (2).ect"));
+        assert_eq!(free_name(&temp_dir.path().join("v1.2"), true).unwrap(), temp_dir.path().join("v1.2 (1)"));
+    }
+}
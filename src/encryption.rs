@@ -2,21 +2,121 @@ use aes_gcm::{
     aead::{Aead, AeadCore, KeyInit, OsRng},
     Aes256Gcm, Key, Nonce,
 };
+use hkdf::Hkdf;
 use pbkdf2::pbkdf2_hmac;
 use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use zeroize::Zeroize;
 
-const PBKDF2_ITERATIONS: u32 = 100_000;
+use crate::metadata::KeyDerivation;
+
+/// PBKDF2 rounds for new files, and for every file written before the header
+/// could record another count.
+pub const DEFAULT_PBKDF2_ITERATIONS: u32 = 100_000;
+/// Most PBKDF2 rounds a header may ask for, so a forged file can't keep the
+/// tool busy for hours.
+pub const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
 const NONCE_SIZE: usize = 12;
 const SALT_SIZE: usize = 32;
+const SUBKEY_INFO: &[u8] = b"ectfy file key";
+
+type KeySlots = HashMap<([u8; SALT_SIZE], u32), Arc<OnceLock<Key<Aes256Gcm>>>>;
+
+/// A password entered for one batch, with the keys derived from it so far by
+/// salt and iterations. PBKDF2 is deliberately slow, so an item that is
+/// encrypted and then verified, files sharing a master key, and files tried
+/// again with the same password derive each key only once. Workers asking
+/// for the same key wait for one derivation; different keys derive in
+/// parallel. The password and its keys are zeroized when it is dropped.
+pub struct Password {
+    text: String,
+    keys: Mutex<KeySlots>,
+}
+
+impl Password {
+    pub fn new(text: String) -> Self {
+        Self { text, keys: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// The PBKDF2 key for `salt` and `iterations`, derived on first use.
+    pub fn derive_key(&self, salt: &[u8; SALT_SIZE], iterations: u32) -> Key<Aes256Gcm> {
+        let slot = self.keys.lock().unwrap_or_else(|e| e.into_inner())
+            .entry((*salt, iterations))
+            .or_default()
+            .clone();
+
+        *slot.get_or_init(|| derive_key(&self.text, salt, iterations))
+    }
+
+    /// Derives the key a file was encrypted with from its header fields.
+    pub fn file_key(&self, salt: &[u8; SALT_SIZE], key_derivation: &KeyDerivation, iterations: u32) -> Key<Aes256Gcm> {
+        let key = self.derive_key(salt, iterations);
+        match key_derivation {
+            KeyDerivation::Pbkdf2 => key,
+            KeyDerivation::Pbkdf2Hkdf { file_salt } => derive_subkey(&key, file_salt),
+        }
+    }
+}
+
+impl From<&str> for Password {
+    fn from(text: &str) -> Self {
+        Self::new(text.to_string())
+    }
+}
+
+impl Drop for Password {
+    fn drop(&mut self) {
+        self.text.zeroize();
+        let keys = self.keys.get_mut().unwrap_or_else(|e| e.into_inner());
+        for slot in keys.values_mut() {
+            if let Some(key) = Arc::get_mut(slot).and_then(OnceLock::get_mut) {
+                key.as_mut_slice().zeroize();
+            }
+        }
+    }
+}
+
+/// Runs PBKDF2-HMAC-SHA256 over `password`. Callers go through
+/// [`Password::derive_key`], which keeps the result for the batch.
+pub fn derive_key(password: &str, salt: &[u8; SALT_SIZE], iterations: u32) -> Key<Aes256Gcm> {
+    let mut key_bytes = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut key_bytes);
+    let key = *Key::<Aes256Gcm>::from_slice(&key_bytes);
+    key_bytes.zeroize();
+    key
+}
 
-pub fn derive_key(password: &str, salt: &[u8; SALT_SIZE]) -> Key<Aes256Gcm> {
+/// Expands a master key into the key of one file with HKDF-SHA256.
+pub fn derive_subkey(master: &Key<Aes256Gcm>, file_salt: &[u8; SALT_SIZE]) -> Key<Aes256Gcm> {
     let mut key_bytes = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ITERATIONS, &mut key_bytes);
-    *Key::<Aes256Gcm>::from_slice(&key_bytes)
+    Hkdf::<Sha256>::new(Some(file_salt), master)
+        .expand(SUBKEY_INFO, &mut key_bytes)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    let key = *Key::<Aes256Gcm>::from_slice(&key_bytes);
+    key_bytes.zeroize();
+    key
 }
 
-pub fn encrypt_data(data: &[u8], password: &str, salt: &[u8; SALT_SIZE]) -> Result<(Vec<u8>, [u8; NONCE_SIZE]), aes_gcm::Error> {
-    let key = derive_key(password, salt);
+/// Encrypts a whole payload at once, as version 1 did, with the only
+/// iteration count it knew. New files are written in segments, so this is
+/// only built for tests of the older format.
+#[cfg(test)]
+pub(crate) fn encrypt_data(
+    data: &[u8],
+    password: &Password,
+    salt: &[u8; SALT_SIZE],
+    key_derivation: &KeyDerivation,
+) -> Result<(Vec<u8>, [u8; NONCE_SIZE]), aes_gcm::Error> {
+    let key = password.file_key(salt, key_derivation, DEFAULT_PBKDF2_ITERATIONS);
     let cipher = Aes256Gcm::new(&key);
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
     
@@ -28,19 +128,25 @@ pub fn encrypt_data(data: &[u8], password: &str, salt: &[u8; SALT_SIZE]) -> Resu
     Ok((ciphertext, nonce_array))
 }
 
+/// Decrypts a whole version 1 payload, which was encrypted as one message.
 pub fn decrypt_data(
     ciphertext: &[u8],
-    password: &str,
+    password: &Password,
     salt: &[u8; SALT_SIZE],
+    key_derivation: &KeyDerivation,
     nonce: &[u8; NONCE_SIZE],
 ) -> Result<Vec<u8>, aes_gcm::Error> {
-    let key = derive_key(password, salt);
+    let key = password.file_key(salt, key_derivation, DEFAULT_PBKDF2_ITERATIONS);
     let cipher = Aes256Gcm::new(&key);
     let nonce = Nonce::from_slice(nonce);
     
     cipher.decrypt(nonce, ciphertext)
 }
 
+pub fn generate_nonce() -> [u8; NONCE_SIZE] {
+    Aes256Gcm::generate_nonce(&mut OsRng).into()
+}
+
 pub fn generate_salt() -> [u8; SALT_SIZE] {
     let mut salt = [0u8; SALT_SIZE];
     use rand::RngCore;
@@ -55,26 +161,60 @@ mod tests {
     #[test]
     fn test_encrypt_decrypt_round_trip() {
         let data = b"Hello, World!";
-        let password = "test_password_123";
+        let password = Password::from("test_password_123");
         let salt = generate_salt();
 
-        let (ciphertext, nonce) = encrypt_data(data, password, &salt).unwrap();
-        let decrypted = decrypt_data(&ciphertext, password, &salt, &nonce).unwrap();
+        let (ciphertext, nonce) = encrypt_data(data, &password, &salt, &KeyDerivation::Pbkdf2).unwrap();
+        let decrypted = decrypt_data(&ciphertext, &password, &salt, &KeyDerivation::Pbkdf2, &nonce).unwrap();
 
         assert_eq!(data, decrypted.as_slice());
     }
 
+    #[test]
+    fn test_hkdf_subkeys_per_file() {
+        let password = Password::from("password");
+        let salt = generate_salt();
+        let first = KeyDerivation::Pbkdf2Hkdf { file_salt: generate_salt() };
+        let second = KeyDerivation::Pbkdf2Hkdf { file_salt: generate_salt() };
+
+        assert_eq!(password.file_key(&salt, &first, DEFAULT_PBKDF2_ITERATIONS), password.file_key(&salt, &first, DEFAULT_PBKDF2_ITERATIONS));
+        assert_ne!(password.file_key(&salt, &first, DEFAULT_PBKDF2_ITERATIONS), password.file_key(&salt, &second, DEFAULT_PBKDF2_ITERATIONS));
+        assert_ne!(password.file_key(&salt, &first, DEFAULT_PBKDF2_ITERATIONS), password.derive_key(&salt, DEFAULT_PBKDF2_ITERATIONS));
+
+        let (ciphertext, nonce) = encrypt_data(b"data", &password, &salt, &first).unwrap();
+        assert_eq!(decrypt_data(&ciphertext, &password, &salt, &first, &nonce).unwrap(), b"data");
+        assert!(decrypt_data(&ciphertext, &password, &salt, &second, &nonce).is_err());
+        assert!(decrypt_data(&ciphertext, &password, &salt, &KeyDerivation::Pbkdf2, &nonce).is_err());
+    }
+
+    #[test]
+    fn test_password_keeps_keys_per_salt_and_iterations() {
+        let password = Password::from("password");
+        let salt = generate_salt();
+        let other_salt = generate_salt();
+
+        let key = password.derive_key(&salt, 1_000);
+        assert_eq!(key, derive_key("password", &salt, 1_000));
+        assert_eq!(password.keys.lock().unwrap().len(), 1);
+        assert_eq!(password.derive_key(&salt, 1_000), key);
+        assert_eq!(password.keys.lock().unwrap().len(), 1, "the second call reuses the key");
+
+        assert_ne!(password.derive_key(&other_salt, 1_000), key);
+        assert_ne!(password.derive_key(&salt, 2_000), key);
+        assert_ne!(Password::from("other").derive_key(&salt, 1_000), key);
+        assert_eq!(password.keys.lock().unwrap().len(), 3);
+    }
+
     #[test]
     fn test_wrong_password_fails() {
         let data = b"Hello, World!";
-        let password = "test_password_123";
-        let wrong_password = "wrong_password";
+        let password = Password::from("test_password_123");
+        let wrong_password = Password::from("wrong_password");
         let salt = generate_salt();
 
-        let (ciphertext, nonce) = encrypt_data(data, password, &salt).unwrap();
-        let result = decrypt_data(&ciphertext, wrong_password, &salt, &nonce);
+        let (ciphertext, nonce) = encrypt_data(data, &password, &salt, &KeyDerivation::Pbkdf2).unwrap();
+        let result = decrypt_data(&ciphertext, &wrong_password, &salt, &KeyDerivation::Pbkdf2, &nonce);
 
         assert!(result.is_err());
     }
 }
-
@@ -1,173 +1,586 @@
+use aes_gcm::{Aes256Gcm, Key};
+use std::fmt;
 use std::fs;
-use std::io::{Read, Write};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use std::thread;
+
+use crate::archive::{
+    check_tar_entries, create_tar_archive, list_tar_entries, refuse_rejected, unpack_checked_tar, ArchiveOptions,
+    ArchiveReport, ExtendedAttributes, ListedEntry, ListedKind, UnreadableEntry,
+};
+use crate::attributes::FileAttributes;
+use crate::atomic::{create_dir_atomic, stage_file, write_file_atomic, StagedFile};
+use crate::conflict::{resolve_conflict, ConflictPolicy, Resolution};
+use crate::encryption::{decrypt_data, generate_nonce, generate_salt, Password, DEFAULT_PBKDF2_ITERATIONS};
+use crate::metadata::{
+    decode_file_payload, encode_file_payload, read_header_from, ContentType, KeyDerivation, Metadata, HEADER_PREFIX_LEN, MAGIC_BYTES,
+    MIN_VERSION, VERSION,
+};
+use crate::pool::default_jobs;
+use crate::segments::{decrypt_first_segment, decrypt_segments, encrypt_segments};
+use crate::verify::{verify_file_against_source, verify_folder_against_source};
+
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Exact output path (`-o`). Only valid for a single input.
+    pub path: Option<PathBuf>,
+    /// Directory to write outputs into instead of next to each input (`--out-dir`).
+    pub dir: Option<PathBuf>,
+    /// What to do when the output already exists.
+    pub on_conflict: ConflictPolicy,
+    /// `dir` may not exist yet; it is created when the output is written.
+    pub create_dir: bool,
+}
 
-use crate::archive::{create_tar_archive, extract_tar_archive};
-use crate::encryption::{decrypt_data, encrypt_data, generate_salt};
-use crate::metadata::{ContentType, Metadata, MAGIC_BYTES, VERSION};
+impl OutputOptions {
+    /// Works out where the output for `input` goes, given the name it would get
+    /// next to the input.
+    pub fn target_path(&self, input: &Path, default_name: &str) -> Result<PathBuf, String> {
+        let output_path = if let Some(path) = &self.path {
+            let parent = path.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            if !parent.is_dir() {
+                return Err(format!("Output directory does not exist: {}", parent.display()));
+            }
+            path.clone()
+        } else if let Some(dir) = &self.dir {
+            if !self.create_dir && !dir.is_dir() {
+                return Err(format!("Output directory does not exist: {}", dir.display()));
+            }
+            dir.join(default_name)
+        } else {
+            input.parent()
+                .ok_or_else(|| "Invalid file path".to_string())?
+                .join(default_name)
+        };
+
+        if output_path == input {
+            return Err(format!("Output would overwrite the input: {}", input.display()));
+        }
 
-pub fn is_encrypted_file(path: &Path) -> bool {
+        Ok(output_path)
+    }
+
+    /// Like [`OutputOptions::target_path`], with the conflict policy applied.
+    fn resolve(&self, input: &Path, default_name: &str, is_dir: bool) -> Result<Resolution, String> {
+        let output_path = self.target_path(input, default_name)?;
+        resolve_conflict(&output_path, is_dir, self.on_conflict)
+    }
+
+    /// Caps the number of outputs written at once; conflict prompts can only
+    /// be answered one at a time.
+    pub fn max_jobs(&self, jobs: usize) -> usize {
+        if self.on_conflict == ConflictPolicy::Ask { 1 } else { jobs }
+    }
+
+    /// Options for one file of a recursive operation rooted at `root`: outputs
+    /// keep their position relative to `root` inside the output directory,
+    /// which is only created once something is written there.
+    pub fn for_nested(&self, root: &Path, file: &Path) -> Result<Self, String> {
+        if self.path.is_some() {
+            return Err("An output path can only be used with a single file; use an output directory instead".to_string());
+        }
+
+        let Some(dir) = &self.dir else {
+            return Ok(self.clone());
+        };
+
+        let relative_parent = file.parent()
+            .and_then(|parent| parent.strip_prefix(root).ok())
+            .unwrap_or(Path::new(""));
+        Ok(Self { dir: Some(dir.join(relative_parent)), create_dir: true, ..self.clone() })
+    }
+}
+
+/// Why decrypting a file failed. Only a wrong password is worth asking for
+/// another one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecryptError {
+    /// The password does not authenticate the start of the payload, which a
+    /// damaged start can't be told apart from.
+    WrongPassword,
+    /// The password was accepted, but a later part of the payload failed
+    /// authentication.
+    Damaged(String),
+    /// Anything else, like failing to read the input or write the output.
+    Other(String),
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptError::WrongPassword => f.write_str("Incorrect password or corrupted file"),
+            DecryptError::Damaged(detail) => write!(f, "The file is damaged: {}", detail),
+            DecryptError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+impl From<String> for DecryptError {
+    fn from(message: String) -> Self {
+        DecryptError::Other(message)
+    }
+}
+
+impl From<DecryptError> for String {
+    fn from(error: DecryptError) -> Self {
+        error.to_string()
+    }
+}
+
+/// What happened to a single input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The output was written to this path.
+    Written(PathBuf),
+    /// Nothing was done because this output already exists.
+    Skipped(PathBuf),
+    /// The output was written without some entries, either unreadable or
+    /// filtered out, so the original was kept.
+    Incomplete(PathBuf, ArchiveReport),
+}
+
+/// Where the output went, for tests that don't care whether it was written.
+#[cfg(test)]
+impl Outcome {
+    pub fn path(&self) -> &Path {
+        match self {
+            Outcome::Written(path) | Outcome::Skipped(path) | Outcome::Incomplete(path, _) => path,
+        }
+    }
+
+    pub fn into_path(self) -> PathBuf {
+        match self {
+            Outcome::Written(path) | Outcome::Skipped(path) | Outcome::Incomplete(path, _) => path,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncryptOptions {
+    /// Re-read and authenticate the output before the original is deleted.
+    pub verify: bool,
+    /// Leave the plaintext original in place after encrypting.
+    pub keep: bool,
+    pub output: OutputOptions,
+    /// How folder archives treat links and special files.
+    pub archive: ArchiveOptions,
+    /// Salt of a master key shared by every output of one operation. Each
+    /// output then gets its own key from the master key through HKDF, so
+    /// PBKDF2 runs once instead of once per file.
+    pub master_salt: Option<[u8; 32]>,
+    /// Threads used for the segments of one output.
+    pub jobs: usize,
+}
+
+impl Default for EncryptOptions {
+    fn default() -> Self {
+        Self {
+            verify: true,
+            keep: false,
+            output: OutputOptions::default(),
+            archive: ArchiveOptions::default(),
+            master_salt: None,
+            jobs: default_jobs(),
+        }
+    }
+}
+
+impl EncryptOptions {
+    /// Picks the salt and key derivation for a new output.
+    fn key_derivation(&self) -> ([u8; 32], KeyDerivation) {
+        match self.master_salt {
+            Some(master_salt) => (master_salt, KeyDerivation::Pbkdf2Hkdf { file_salt: generate_salt() }),
+            None => (generate_salt(), KeyDerivation::Pbkdf2),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecryptOptions {
+    /// Leave the `.ect` file in place after decrypting.
+    pub keep_encrypted: bool,
+    /// Restore permissions, timestamps and ownership stored with the file.
+    pub preserve: bool,
+    pub output: OutputOptions,
+    /// Extended metadata restored from folder archives, unless `preserve` is off.
+    pub restore: ExtendedAttributes,
+    /// Threads used for the segments of one input.
+    pub jobs: usize,
+}
+
+impl Default for DecryptOptions {
+    fn default() -> Self {
+        Self {
+            keep_encrypted: false,
+            preserve: true,
+            output: OutputOptions::default(),
+            restore: ExtendedAttributes::default(),
+            jobs: default_jobs(),
+        }
+    }
+}
+
+/// The name an encrypted copy of `path` gets: the full file or folder name
+/// with `.ect` appended, so `report.pdf` and `report.docx` stay distinct.
+pub fn encrypted_name(path: &Path) -> Result<String, String> {
+    let name = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("Invalid file name: {}", path.display()))?;
+
+    Ok(format!("{}.ect", name))
+}
+
+/// Whether `path` ends in the `.ect` extension encrypted outputs get.
+pub fn has_encrypted_extension(path: &Path) -> bool {
     path.extension()
         .and_then(|ext| ext.to_str())
         .map(|ext| ext == "ect")
         .unwrap_or(false)
 }
 
-pub fn read_encrypted_file(path: &Path) -> Result<(Metadata, Vec<u8>), String> {
-    let mut file = fs::File::open(path)
-        .map_err(|e| format!("Failed to open file: {}", e))?;
+/// Whether `path` is a file that starts with the magic bytes, version and
+/// metadata length of an encrypted file, whatever it is called. Only those
+/// bytes are read; whether the rest parses is left to decryption, so a file
+/// from a newer version is reported as such instead of encrypted again.
+pub fn is_encrypted_file(path: &Path) -> bool {
+    let mut prefix = [0u8; HEADER_PREFIX_LEN];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut prefix))
+        .is_ok_and(|_| prefix.starts_with(MAGIC_BYTES) && prefix[4] >= MIN_VERSION)
+}
 
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)
-        .map_err(|e| format!("Failed to read file: {}", e))?;
+/// An encrypted file whose header has been read. The handle stays open, so
+/// the payload is read from where the header ended rather than by opening
+/// and parsing the file again.
+pub struct EncryptedFile {
+    pub path: PathBuf,
+    pub metadata: Metadata,
+    reader: BufReader<fs::File>,
+    payload_start: u64,
+}
 
-    if buffer.len() < 9 {
-        return Err("File appears to be corrupted (too short)".to_string());
+impl EncryptedFile {
+    /// Opens `path` and reads only its header; see [`read_header_from`].
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = fs::File::open(path)
+            .map_err(|e| format!("Failed to open file: {}", e))?;
+        let mut reader = BufReader::new(file);
+        let (metadata, payload_start) = read_header_from(&mut reader)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            metadata,
+            reader,
+            payload_start,
+        })
     }
 
-    let magic = &buffer[0..4];
-    if magic != MAGIC_BYTES {
-        return Err("File appears to be corrupted (invalid magic bytes)".to_string());
+    /// Size of the encrypted payload after the header, in bytes.
+    pub fn payload_len(&self) -> Result<u64, String> {
+        let file_len = self.reader.get_ref().metadata()
+            .map_err(|e| format!("Failed to read file: {}", e))?
+            .len();
+        Ok(file_len.saturating_sub(self.payload_start))
     }
 
-    let version = buffer[4];
-    if version != VERSION {
-        return Err(format!("Unsupported file version: {}", version));
+    /// Checks `password` against the payload, decrypting no more than needed
+    /// to do so. Can be called again, with another password, if it fails.
+    pub fn payload(&mut self, password: &Password) -> Result<Payload<'_>, DecryptError> {
+        self.reader.seek(SeekFrom::Start(self.payload_start))
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+        let metadata = &self.metadata;
+
+        if metadata.version < 2 {
+            let mut encrypted_data = Vec::new();
+            self.reader.read_to_end(&mut encrypted_data)
+                .map_err(|e| format!("Failed to read file: {}", e))?;
+            let head = decrypt_data(&encrypted_data, password, &metadata.salt, &metadata.key_derivation, &metadata.nonce)
+                .map_err(|_| DecryptError::WrongPassword)?;
+            return Ok(Payload { head, rest: None });
+        }
+
+        let key = password.file_key(&metadata.salt, &metadata.key_derivation, metadata.kdf_iterations);
+        let (first_segment, head) = decrypt_first_segment(&key, &metadata.nonce, &mut self.reader)?;
+        Ok(Payload {
+            head,
+            rest: Some(SegmentedRest { key, nonce: metadata.nonce, first_segment, reader: &mut self.reader }),
+        })
     }
+}
 
-    let metadata_len = u32::from_le_bytes([
-        buffer[5], buffer[6], buffer[7], buffer[8]
-    ]) as usize;
+/// Reads only the header of an encrypted file, however large its payload.
+pub fn read_header(path: &Path) -> Result<Metadata, String> {
+    EncryptedFile::open(path).map(|file| file.metadata)
+}
 
-    if buffer.len() < 9 + metadata_len {
-        return Err("File appears to be corrupted (metadata length invalid)".to_string());
+/// Writes the header for `metadata`, then lets `write_payload` write the
+/// encrypted payload. Nothing is at `path` until the staged file is
+/// committed, so it can be verified first.
+pub fn stage_encrypted_file<F>(path: &Path, metadata: &Metadata, replace: bool, write_payload: F) -> Result<StagedFile, String>
+where
+    F: FnOnce(&mut fs::File) -> Result<(), String>,
+{
+    let metadata_bytes = metadata.serialize()
+        .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
+
+    stage_file(path, replace, |file| {
+        file.write_all(MAGIC_BYTES)?;
+        file.write_all(&[VERSION])?;
+
+        let metadata_len = metadata_bytes.len() as u32;
+        file.write_all(&metadata_len.to_le_bytes())?;
+
+        file.write_all(&metadata_bytes)?;
+        write_payload(file).map_err(io::Error::other)
+    })
+}
+
+/// Drops the first `skip` bytes written through it.
+struct SkipWriter<'a, W: ?Sized> {
+    inner: &'a mut W,
+    skip: usize,
+}
+
+impl<W: Write + ?Sized> Write for SkipWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skipped = self.skip.min(buf.len());
+        self.skip -= skipped;
+        if skipped == buf.len() {
+            return Ok(skipped);
+        }
+        Ok(skipped + self.inner.write(&buf[skipped..])?)
     }
 
-    let metadata_bytes = &buffer[9..9 + metadata_len];
-    let metadata = Metadata::deserialize(metadata_bytes)
-        .map_err(|e| format!("Failed to deserialize metadata: {}", e))?;
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-    let encrypted_data = &buffer[9 + metadata_len..];
+/// An encrypted payload whose password has been checked. Version 1 payloads
+/// are decrypted whole; segmented payloads only have their
+/// first segment decrypted until [`Payload::write_to`] streams the rest.
+pub struct Payload<'a> {
+    head: Vec<u8>,
+    rest: Option<SegmentedRest<'a>>,
+}
 
-    Ok((metadata, encrypted_data.to_vec()))
+struct SegmentedRest<'a> {
+    key: Key<Aes256Gcm>,
+    nonce: [u8; 12],
+    first_segment: Vec<u8>,
+    reader: &'a mut BufReader<fs::File>,
 }
 
-pub fn write_encrypted_file(path: &Path, metadata: &Metadata, encrypted_data: &[u8]) -> Result<(), String> {
-    let metadata_bytes = metadata.serialize()
-        .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
+impl Payload<'_> {
+    /// The plaintext decrypted so far: all of it for whole payloads, the
+    /// first segment for segmented ones. Enough to read the file attributes.
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+
+    /// Writes the plaintext to `output` without its first `skip` bytes,
+    /// authenticating every segment on the way.
+    pub fn write_to<W: Write + ?Sized>(self, output: &mut W, skip: usize, jobs: usize) -> Result<(), DecryptError> {
+        let mut output = SkipWriter { inner: output, skip };
+        match self.rest {
+            None => output.write_all(&self.head)
+                .map_err(|e| DecryptError::Other(format!("Failed to write output: {}", e))),
+            Some(rest) => {
+                let input = Cursor::new(rest.first_segment).chain(rest.reader);
+                decrypt_segments(&rest.key, &rest.nonce, input, &mut output, jobs)
+            }
+        }
+    }
 
-    let mut file = fs::File::create(path)
-        .map_err(|e| format!("Failed to create file: {}", e))?;
+    /// Decrypts the plaintext on another thread while `read` consumes it
+    /// through a pipe on this one, so it can be fed to something that reads,
+    /// like a tar parser or another encryption, without being held in memory
+    /// or written to disk. Whatever `read` leaves is drained, so the result
+    /// is only `Ok` once every segment has been authenticated. A damaged
+    /// segment is reported before whatever `read` made of the data.
+    pub fn read_with<T, F>(self, jobs: usize, read: F) -> Result<T, DecryptError>
+    where
+        F: FnOnce(&mut io::PipeReader) -> Result<T, String>,
+    {
+        let (mut reader, mut writer) = io::pipe()
+            .map_err(|e| format!("Failed to create pipe: {}", e))?;
+
+        thread::scope(|scope| {
+            let decrypting = scope.spawn(move || self.write_to(&mut writer, 0, jobs));
+            let result = read(&mut reader).and_then(|value| {
+                io::copy(&mut reader, &mut io::sink())
+                    .map_err(|e| format!("Failed to read decrypted data: {}", e))?;
+                Ok(value)
+            });
+            // Without a reader, a decrypting thread that is still writing
+            // fails instead of blocking on a full pipe.
+            drop(reader);
+            let decrypted = decrypting.join()
+                .unwrap_or_else(|_| Err(DecryptError::Other("Decryption thread panicked".to_string())));
+            match (decrypted, result) {
+                (Err(e @ DecryptError::Damaged(_)), _) => Err(e),
+                (_, Err(e)) => Err(DecryptError::Other(e)),
+                (decrypted, Ok(value)) => decrypted.map(|_| value),
+            }
+        })
+    }
+}
 
-    file.write_all(MAGIC_BYTES)
-        .map_err(|e| format!("Failed to write magic bytes: {}", e))?;
-    file.write_all(&[VERSION])
-        .map_err(|e| format!("Failed to write version: {}", e))?;
-    
-    let metadata_len = metadata_bytes.len() as u32;
-    file.write_all(&metadata_len.to_le_bytes())
-        .map_err(|e| format!("Failed to write metadata length: {}", e))?;
-    
-    file.write_all(&metadata_bytes)
-        .map_err(|e| format!("Failed to write metadata: {}", e))?;
-    file.write_all(encrypted_data)
-        .map_err(|e| format!("Failed to write encrypted data: {}", e))?;
+/// Counts the bytes written through it.
+struct CountingWriter(u64);
 
-    Ok(())
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-pub fn encrypt_file(path: &Path, password: &str, helper_question: &str) -> Result<PathBuf, String> {
-    let data = fs::read(path)
-        .map_err(|e| format!("Failed to read file: {}", e))?;
+/// Lists what decrypting `file` would write, without writing anything: the
+/// entries of a folder archive, or the file itself with its size. The whole
+/// payload is authenticated on the way.
+pub fn list_opened_file(file: &mut EncryptedFile, password: &Password, jobs: usize) -> Result<Vec<ListedEntry>, DecryptError> {
+    let (version, content_type) = (file.metadata.version, file.metadata.content_type.clone());
+    let path = PathBuf::from(&file.metadata.original_name);
+    let payload = file.payload(password)?;
 
-    let salt = generate_salt();
-    let (encrypted_data, nonce) = encrypt_data(&data, password, &salt)
-        .map_err(|e| format!("Encryption failed: {}", e))?;
+    if let ContentType::Folder = content_type {
+        return payload.read_with(jobs, |reader| list_tar_entries(reader));
+    }
+
+    let (_, contents) = decode_file_payload(version, payload.head())?;
+    let attributes_len = payload.head().len() - contents.len();
+    let mut counter = CountingWriter(0);
+    payload.write_to(&mut counter, attributes_len, jobs)?;
+    Ok(vec![ListedEntry { path, kind: ListedKind::File, size: counter.0 }])
+}
 
+/// Runs a verification pass over a staged output, then moves it into place.
+/// If verification fails the staged output is thrown away, leaving both the
+/// original and anything the output would have replaced untouched.
+pub(crate) fn verify_and_commit<F>(staged: StagedFile, verify: F) -> Result<(), String>
+where
+    F: FnOnce(&Path) -> Result<(), String>,
+{
+    verify(staged.temp_path())
+        .map_err(|e| format!("Verification failed, original kept: {}", e))?;
+    staged.commit()
+}
+
+pub fn encrypt_file(path: &Path, password: &Password, helper_question: &str, options: &EncryptOptions) -> Result<Outcome, String> {
     let original_name = path.file_name()
         .and_then(|n| n.to_str())
         .ok_or_else(|| "Invalid filename".to_string())?
         .to_string();
 
-    let metadata = Metadata::new(nonce, salt, helper_question.to_string(), original_name, ContentType::File);
+    let (output_path, replace) = match options.output.resolve(path, &encrypted_name(path)?, false)? {
+        Resolution::Create(output_path) => (output_path, false),
+        Resolution::Replace(output_path) => (output_path, true),
+        Resolution::Skip(output_path) => return Ok(Outcome::Skipped(output_path)),
+    };
 
-    let output_path = path.with_extension("ect");
-    write_encrypted_file(&output_path, &metadata, &encrypted_data)?;
+    let attributes = fs::metadata(path)
+        .map(|metadata| FileAttributes::capture(&metadata))
+        .map_err(|e| format!("Failed to read file attributes: {}", e))?;
+    let source = fs::File::open(path)
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    let attributes_prefix = encode_file_payload(&attributes, &[])?;
 
-    fs::remove_file(path)
-        .map_err(|e| format!("Failed to delete original file: {}", e))?;
+    let (salt, key_derivation) = options.key_derivation();
+    let nonce = generate_nonce();
+    let key = password.file_key(&salt, &key_derivation, DEFAULT_PBKDF2_ITERATIONS);
 
-    Ok(output_path)
-}
+    let metadata = Metadata::new(nonce, salt, helper_question.to_string(), original_name, ContentType::File)
+        .with_key_derivation(key_derivation);
 
-pub fn decrypt_file(path: &Path, password: &str) -> Result<PathBuf, String> {
-    let (metadata, encrypted_data) = read_encrypted_file(path)?;
+    let staged = stage_encrypted_file(&output_path, &metadata, replace, |file| {
+        encrypt_segments(&key, &nonce, Cursor::new(attributes_prefix).chain(source), file, options.jobs)
+    })?;
 
-    let decrypted_data = decrypt_data(&encrypted_data, password, &metadata.salt, &metadata.nonce)
-        .map_err(|_| "Incorrect password or corrupted file".to_string())?;
+    if options.verify {
+        verify_and_commit(staged, |staged_path| verify_file_against_source(staged_path, password, path, options.jobs))?;
+    } else {
+        staged.commit()?;
+    }
 
-    let output_path = path.parent()
-        .ok_or_else(|| "Invalid file path".to_string())?
-        .join(&metadata.original_name);
+    if !options.keep {
+        fs::remove_file(path)
+            .map_err(|e| format!("Failed to delete original file: {}", e))?;
+    }
 
-    fs::write(&output_path, decrypted_data)
-        .map_err(|e| format!("Failed to write decrypted file: {}", e))?;
+    Ok(Outcome::Written(output_path))
+}
 
-    fs::remove_file(path)
-        .map_err(|e| format!("Failed to delete encrypted file: {}", e))?;
+pub fn decrypt_file(path: &Path, password: &Password, options: &DecryptOptions) -> Result<Outcome, DecryptError> {
+    decrypt_opened_file(&mut EncryptedFile::open(path)?, password, options)
+}
 
-    Ok(output_path)
+/// Decrypts a file payload, continuing from the already opened `file`.
+pub fn decrypt_opened_file(file: &mut EncryptedFile, password: &Password, options: &DecryptOptions) -> Result<Outcome, DecryptError> {
+    let (path, metadata) = (file.path.clone(), file.metadata.clone());
+
+    // Authenticate before resolving the output, so a wrong password never
+    // leads to a conflict prompt.
+    let payload = file.payload(password)?;
+    let (attributes, contents) = decode_file_payload(metadata.version, payload.head())?;
+    let attributes_len = payload.head().len() - contents.len();
+
+    let (output_path, replace) = match options.output.resolve(&path, &metadata.original_name, false)? {
+        Resolution::Create(output_path) => (output_path, false),
+        Resolution::Replace(output_path) => (output_path, true),
+        Resolution::Skip(output_path) => return Ok(Outcome::Skipped(output_path)),
+    };
+
+    write_file_atomic(&output_path, replace, |file| {
+        payload.write_to(file, attributes_len, options.jobs).map_err(io::Error::other)?;
+        if options.preserve {
+            attributes.apply(file)?;
+        }
+        Ok(())
+    })?;
+
+    if !options.keep_encrypted {
+        fs::remove_file(&path)
+            .map_err(|e| format!("Failed to delete encrypted file: {}", e))?;
+    }
+
+    Ok(Outcome::Written(output_path))
 }
 
-pub fn collect_files_recursive(path: &Path) -> Result<Vec<PathBuf>, String> {
+/// Lists the files under `path` that pass the archive filters, along with
+/// any entries that could not be read.
+pub fn collect_files_recursive(path: &Path, options: &ArchiveOptions) -> Result<(Vec<PathBuf>, Vec<UnreadableEntry>), String> {
     let mut files = Vec::new();
+    let mut unreadable = Vec::new();
 
     if path.is_file() {
         files.push(path.to_path_buf());
     } else if path.is_dir() {
-        for entry in WalkDir::new(path)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            if entry.file_type().is_file() {
-                files.push(entry.path().to_path_buf());
+        let mut walker = options.filter.walker(path, options.follow_symlinks)?;
+        for entry in walker.entries() {
+            match entry {
+                Ok(entry) if entry.file_type().is_file() => files.push(entry.path().to_path_buf()),
+                Ok(_) => {}
+                Err(e) => unreadable.push(UnreadableEntry::from_walk_error(&e, path)),
             }
         }
     } else {
         return Err(format!("Path does not exist: {}", path.display()));
     }
 
-    Ok(files)
-}
-
-pub fn encrypt_folder(path: &Path, password: &str, helper_question: &str) -> Result<Vec<PathBuf>, String> {
-    let files = collect_files_recursive(path)?;
-    let mut encrypted_files = Vec::new();
-
-    for file in files {
-        let encrypted = encrypt_file(&file, password, helper_question)?;
-        encrypted_files.push(encrypted);
-    }
-
-    Ok(encrypted_files)
+    Ok((files, unreadable))
 }
 
-pub fn decrypt_folder(path: &Path, password: &str) -> Result<Vec<PathBuf>, String> {
-    let files = collect_files_recursive(path)?;
-    let mut decrypted_files = Vec::new();
-
-    for file in files {
-        if is_encrypted_file(&file) {
-            let decrypted = decrypt_file(&file, password)?;
-            decrypted_files.push(decrypted);
-        }
-    }
-
-    Ok(decrypted_files)
-}
-
-pub fn encrypt_folder_archive(path: &Path, password: &str, helper_question: &str) -> Result<PathBuf, String> {
+pub fn encrypt_folder_archive(path: &Path, password: &Password, helper_question: &str, options: &EncryptOptions) -> Result<Outcome, String> {
     if !path.is_dir() {
         return Err("Path is not a directory".to_string());
     }
@@ -177,60 +590,114 @@ pub fn encrypt_folder_archive(path: &Path, password: &str, helper_question: &str
         .ok_or_else(|| "Invalid folder name".to_string())?
         .to_string();
 
-    let tar_data = create_tar_archive(path)
+    let (output_path, replace) = match options.output.resolve(path, &encrypted_name(path)?, false)? {
+        Resolution::Create(output_path) => (output_path, false),
+        Resolution::Replace(output_path) => (output_path, true),
+        Resolution::Skip(output_path) => return Ok(Outcome::Skipped(output_path)),
+    };
+
+    let (tar_data, report) = create_tar_archive(path, &options.archive)
         .map_err(|e| format!("Failed to create tar archive: {}", e))?;
 
-    let salt = generate_salt();
-    let (encrypted_data, nonce) = encrypt_data(&tar_data, password, &salt)
-        .map_err(|e| format!("Encryption failed: {}", e))?;
+    let (salt, key_derivation) = options.key_derivation();
+    let nonce = generate_nonce();
+    let key = password.file_key(&salt, &key_derivation, DEFAULT_PBKDF2_ITERATIONS);
 
-    let metadata = Metadata::new(nonce, salt, helper_question.to_string(), folder_name.clone(), ContentType::Folder);
+    let metadata = Metadata::new(nonce, salt, helper_question.to_string(), folder_name, ContentType::Folder)
+        .with_key_derivation(key_derivation);
 
-    let output_path = path.parent()
-        .ok_or_else(|| "Invalid folder path".to_string())?
-        .join(format!("{}.ect", folder_name));
+    let staged = stage_encrypted_file(&output_path, &metadata, replace, |file| {
+        encrypt_segments(&key, &nonce, tar_data.as_slice(), file, options.jobs)
+    })?;
 
-    write_encrypted_file(&output_path, &metadata, &encrypted_data)?;
+    if options.verify {
+        verify_and_commit(staged, |staged_path| {
+            verify_folder_against_source(staged_path, password, path, &options.archive, options.jobs)
+        })?;
+    } else {
+        staged.commit()?;
+    }
 
-    fs::remove_dir_all(path)
-        .map_err(|e| format!("Failed to delete original folder: {}", e))?;
+    // Deleting the folder would take the entries left out of the archive with it.
+    if !report.is_complete() {
+        return Ok(Outcome::Incomplete(output_path, report));
+    }
+
+    if !options.keep {
+        fs::remove_dir_all(path)
+            .map_err(|e| format!("Failed to delete original folder: {}", e))?;
+    }
 
-    Ok(output_path)
+    Ok(Outcome::Written(output_path))
 }
 
-pub fn decrypt_folder_archive(path: &Path, password: &str) -> Result<PathBuf, String> {
-    let (metadata, encrypted_data) = read_encrypted_file(path)?;
+/// Opens and decrypts the folder archive at `path`, for tests that don't
+/// need the opened file.
+#[cfg(test)]
+pub(crate) fn decrypt_folder_archive(path: &Path, password: &Password, options: &DecryptOptions) -> Result<Outcome, DecryptError> {
+    decrypt_opened_folder_archive(&mut EncryptedFile::open(path)?, password, options)
+}
+
+/// Decrypts a folder archive, continuing from the already opened `file`.
+pub fn decrypt_opened_folder_archive(file: &mut EncryptedFile, password: &Password, options: &DecryptOptions) -> Result<Outcome, DecryptError> {
+    let (path, metadata) = (file.path.clone(), file.metadata.clone());
 
     if !matches!(metadata.content_type, ContentType::Folder) {
-        return Err("File is not a folder archive".to_string());
+        return Err(DecryptError::Other("File is not a folder archive".to_string()));
     }
 
-    let decrypted_data = decrypt_data(&encrypted_data, password, &metadata.salt, &metadata.nonce)
-        .map_err(|_| "Incorrect password or corrupted file".to_string())?;
-
-    let output_path = path.parent()
-        .ok_or_else(|| "Invalid file path".to_string())?
-        .join(&metadata.original_name);
-
-    extract_tar_archive(&decrypted_data, &output_path)
-        .map_err(|e| format!("Failed to extract tar archive: {}", e))?;
-
-    fs::remove_file(path)
-        .map_err(|e| format!("Failed to delete encrypted file: {}", e))?;
+    // The archive is decrypted twice rather than held in memory: the first
+    // pass authenticates every segment and checks the entries before any
+    // output is set up, the second streams the same entries into the output.
+    let rejected = file.payload(password)?.read_with(options.jobs, |reader| check_tar_entries(reader))?;
+    refuse_rejected(&rejected)?;
+
+    let (output_path, replace) = match options.output.resolve(&path, &metadata.original_name, true)? {
+        Resolution::Create(output_path) => (output_path, false),
+        Resolution::Replace(output_path) => (output_path, true),
+        Resolution::Skip(output_path) => return Ok(Outcome::Skipped(output_path)),
+    };
+
+    let restore = if options.preserve { options.restore } else { ExtendedAttributes::none() };
+    create_dir_atomic(&output_path, replace, |dir| {
+        file.payload(password)?
+            .read_with(options.jobs, |reader| unpack_checked_tar(reader, dir, &restore))
+            .map_err(|e| format!("Failed to extract tar archive: {}", e))
+    })?;
+
+    if !options.keep_encrypted {
+        fs::remove_file(&path)
+            .map_err(|e| format!("Failed to delete encrypted file: {}", e))?;
+    }
 
-    Ok(output_path)
+    Ok(Outcome::Written(output_path))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::metadata::golden;
     use tempfile::TempDir;
     use std::fs;
 
     #[test]
     fn test_is_encrypted_file() {
-        assert!(is_encrypted_file(Path::new("test.ect")));
-        assert!(!is_encrypted_file(Path::new("test.txt")));
+        assert!(has_encrypted_extension(Path::new("test.ect")));
+        assert!(!has_encrypted_extension(Path::new("test.txt")));
+
+        let temp_dir = TempDir::new().unwrap();
+        let renamed = temp_dir.path().join("renamed.pdf");
+        fs::copy(golden(VERSION, "hello.txt.ect"), &renamed).unwrap();
+        let misnamed = temp_dir.path().join("notes.ect");
+        fs::write(&misnamed, b"just some notes").unwrap();
+        let short = temp_dir.path().join("short.ect");
+        fs::write(&short, b"ECTF").unwrap();
+
+        assert!(is_encrypted_file(&renamed));
+        assert!(!is_encrypted_file(&misnamed));
+        assert!(!is_encrypted_file(&short));
+        assert!(!is_encrypted_file(temp_dir.path()));
+        assert!(!is_encrypted_file(&temp_dir.path().join("missing.ect")));
     }
 
     #[test]
@@ -239,15 +706,16 @@ mod tests {
         let test_file = temp_dir.path().join("test.txt");
         fs::write(&test_file, b"Hello, World!").unwrap();
 
-        let password = "test_password";
+        let password = &Password::from("test_password");
         let helper_question = "What is your favorite color?";
 
-        let encrypted_path = encrypt_file(&test_file, password, helper_question).unwrap();
+        let encrypted_path = encrypt_file(&test_file, password, helper_question, &EncryptOptions::default()).unwrap().into_path();
+        assert_eq!(encrypted_path, temp_dir.path().join("test.txt.ect"));
         assert!(encrypted_path.exists());
         assert!(is_encrypted_file(&encrypted_path));
         assert!(!test_file.exists(), "Original file should be deleted after encryption");
 
-        let decrypted_path = decrypt_file(&encrypted_path, password).unwrap();
+        let decrypted_path = decrypt_file(&encrypted_path, password, &DecryptOptions::default()).unwrap().into_path();
         assert!(decrypted_path.exists());
         assert!(!encrypted_path.exists(), "Encrypted file should be deleted after decryption");
 
@@ -261,11 +729,11 @@ mod tests {
         let test_file = temp_dir.path().join("empty.txt");
         fs::write(&test_file, b"").unwrap();
 
-        let password = "test_password";
+        let password = &Password::from("test_password");
         let helper_question = "Test question";
 
-        let encrypted_path = encrypt_file(&test_file, password, helper_question).unwrap();
-        let decrypted_path = decrypt_file(&encrypted_path, password).unwrap();
+        let encrypted_path = encrypt_file(&test_file, password, helper_question, &EncryptOptions::default()).unwrap().into_path();
+        let decrypted_path = decrypt_file(&encrypted_path, password, &DecryptOptions::default()).unwrap().into_path();
 
         let content = fs::read(&decrypted_path).unwrap();
         assert_eq!(content, b"");
@@ -278,11 +746,11 @@ mod tests {
         let binary_data = vec![0u8, 1u8, 2u8, 255u8, 128u8, 64u8];
         fs::write(&test_file, &binary_data).unwrap();
 
-        let password = "test_password";
+        let password = &Password::from("test_password");
         let helper_question = "Test question";
 
-        let encrypted_path = encrypt_file(&test_file, password, helper_question).unwrap();
-        let decrypted_path = decrypt_file(&encrypted_path, password).unwrap();
+        let encrypted_path = encrypt_file(&test_file, password, helper_question, &EncryptOptions::default()).unwrap().into_path();
+        let decrypted_path = decrypt_file(&encrypted_path, password, &DecryptOptions::default()).unwrap().into_path();
 
         let content = fs::read(&decrypted_path).unwrap();
         assert_eq!(content, binary_data);
@@ -294,11 +762,11 @@ mod tests {
         let test_file = temp_dir.path().join("test file with spaces.txt");
         fs::write(&test_file, b"Content").unwrap();
 
-        let password = "test_password";
+        let password = &Password::from("test_password");
         let helper_question = "Test question";
 
-        let encrypted_path = encrypt_file(&test_file, password, helper_question).unwrap();
-        let decrypted_path = decrypt_file(&encrypted_path, password).unwrap();
+        let encrypted_path = encrypt_file(&test_file, password, helper_question, &EncryptOptions::default()).unwrap().into_path();
+        let decrypted_path = decrypt_file(&encrypted_path, password, &DecryptOptions::default()).unwrap().into_path();
 
         assert!(decrypted_path.file_name().unwrap().to_str().unwrap().contains("test file with spaces"));
         let content = fs::read(&decrypted_path).unwrap();
@@ -306,33 +774,321 @@ mod tests {
     }
 
     #[test]
-    fn test_encrypt_decrypt_folder() {
+    fn test_encrypt_decrypt_folder_archive_round_trip() {
         let temp_dir = TempDir::new().unwrap();
-        let subdir = temp_dir.path().join("subdir");
-        fs::create_dir(&subdir).unwrap();
-
-        let file1 = temp_dir.path().join("file1.txt");
-        let file2 = subdir.join("file2.txt");
-        fs::write(&file1, b"File 1 content").unwrap();
-        fs::write(&file2, b"File 2 content").unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir_all(folder.join("subdir")).unwrap();
+        fs::write(folder.join("file1.txt"), b"File 1 content").unwrap();
+        fs::write(folder.join("subdir").join("file2.txt"), b"File 2 content").unwrap();
 
-        let password = "test_password";
+        let password = &Password::from("test_password");
         let helper_question = "Test question";
 
-        let encrypted_files = encrypt_folder(temp_dir.path(), password, helper_question).unwrap();
-        assert_eq!(encrypted_files.len(), 2);
-        assert!(!file1.exists(), "Original file1 should be deleted after encryption");
-        assert!(!file2.exists(), "Original file2 should be deleted after encryption");
+        let encrypted_path = encrypt_folder_archive(&folder, password, helper_question, &EncryptOptions::default()).unwrap().into_path();
+        assert!(!folder.exists(), "Original folder should be deleted after encryption");
+
+        let decrypted_path = decrypt_folder_archive(&encrypted_path, password, &DecryptOptions::default()).unwrap().into_path();
+        assert_eq!(decrypted_path, folder);
+        assert!(!encrypted_path.exists(), "Encrypted file should be deleted after decryption");
+        assert_eq!(fs::read(folder.join("subdir").join("file2.txt")).unwrap(), b"File 2 content");
+
+        let leftovers: Vec<_> = fs::read_dir(temp_dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(leftovers.len(), 1, "No temp files should be left behind: {:?}", leftovers);
+    }
 
-        for encrypted_file in &encrypted_files {
-            assert!(is_encrypted_file(encrypted_file));
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_entries_keep_folder() {
+        let password = Password::from("test_password");
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir(&folder).unwrap();
+        fs::write(folder.join("file.txt"), b"content").unwrap();
+        std::os::unix::fs::symlink("missing.txt", folder.join("dangling")).unwrap();
+
+        let mut encrypt_options = EncryptOptions::default();
+        encrypt_options.archive.follow_symlinks = true;
+        assert!(encrypt_folder_archive(&folder, &password, "Test question", &encrypt_options).is_err());
+        assert!(!temp_dir.path().join("folder.ect").exists(), "Nothing should be written when entries are unreadable");
+
+        encrypt_options.archive.ignore_unreadable = true;
+        let outcome = encrypt_folder_archive(&folder, &password, "Test question", &encrypt_options).unwrap();
+        match outcome {
+            Outcome::Incomplete(output, report) => {
+                assert_eq!(output, temp_dir.path().join("folder.ect"));
+                assert_eq!(report.unreadable.len(), 1);
+            }
+            other => panic!("Expected an incomplete outcome, got {:?}", other),
         }
+        assert!(folder.join("file.txt").exists(), "The folder should be kept when entries were skipped");
+    }
+
+    #[test]
+    fn test_folder_filters() {
+        let password = Password::from("test_password");
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("repo");
+        fs::create_dir_all(folder.join("target").join("debug")).unwrap();
+        fs::write(folder.join("main.rs"), b"fn main() {}").unwrap();
+        fs::write(folder.join("target").join("debug").join("app"), b"binary").unwrap();
+
+        let mut encrypt_options = EncryptOptions::default();
+        encrypt_options.archive.filter.exclude = vec!["target".to_string()];
+        let outcome = encrypt_folder_archive(&folder, &password, "Test question", &encrypt_options).unwrap();
+        let Outcome::Incomplete(encrypted_path, report) = outcome else {
+            panic!("Expected an incomplete outcome, got {:?}", outcome);
+        };
+        assert_eq!(report.excluded, 1);
+        assert!(folder.join("target").exists(), "The folder should be kept when entries were filtered out");
+
+        let decrypt_options = DecryptOptions {
+            output: OutputOptions { dir: Some(temp_dir.path().join("out")), ..OutputOptions::default() },
+            ..DecryptOptions::default()
+        };
+        fs::create_dir(temp_dir.path().join("out")).unwrap();
+        let decrypted_path = decrypt_folder_archive(&encrypted_path, &password, &decrypt_options).unwrap().into_path();
+        assert_eq!(fs::read(decrypted_path.join("main.rs")).unwrap(), b"fn main() {}");
+        assert!(!decrypted_path.join("target").exists());
+        assert!(!decrypted_path.join("pax_global_header").exists());
+    }
+
+    #[test]
+    fn test_keep_originals() {
+        let temp_dir = TempDir::new().unwrap();
+        let test_file = temp_dir.path().join("test.txt");
+        fs::write(&test_file, b"Keep me").unwrap();
+
+        let password = &Password::from("test_password");
+        let encrypt_options = EncryptOptions { keep: true, ..EncryptOptions::default() };
+        let encrypted_path = encrypt_file(&test_file, password, "Test question", &encrypt_options).unwrap().into_path();
+        assert!(test_file.exists(), "Original file should be kept");
+
+        fs::remove_file(&test_file).unwrap();
+        let decrypt_options = DecryptOptions { keep_encrypted: true, ..DecryptOptions::default() };
+        let decrypted_path = decrypt_file(&encrypted_path, password, &decrypt_options).unwrap().into_path();
+        assert!(encrypted_path.exists(), "Encrypted file should be kept");
+        assert_eq!(fs::read(&decrypted_path).unwrap(), b"Keep me");
+    }
+
+    #[test]
+    fn test_output_path_and_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let test_file = temp_dir.path().join("test.txt");
+        let out_dir = temp_dir.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        fs::write(&test_file, b"Elsewhere").unwrap();
+
+        let password = &Password::from("test_password");
+        let encrypt_options = EncryptOptions {
+            output: OutputOptions { path: Some(out_dir.join("custom.ect")), ..OutputOptions::default() },
+            ..EncryptOptions::default()
+        };
+        let encrypted_path = encrypt_file(&test_file, password, "Test question", &encrypt_options).unwrap().into_path();
+        assert_eq!(encrypted_path, out_dir.join("custom.ect"));
+
+        let decrypt_options = DecryptOptions {
+            output: OutputOptions { dir: Some(out_dir.clone()), ..OutputOptions::default() },
+            ..DecryptOptions::default()
+        };
+        let decrypted_path = decrypt_file(&encrypted_path, password, &decrypt_options).unwrap().into_path();
+        assert_eq!(decrypted_path, out_dir.join("test.txt"));
+        assert_eq!(fs::read(&decrypted_path).unwrap(), b"Elsewhere");
+
+        fs::write(&test_file, b"Again").unwrap();
+        let encrypted_path = encrypt_file(&test_file, password, "Test question", &EncryptOptions::default()).unwrap().into_path();
+        let result = decrypt_file(&encrypted_path, password, &decrypt_options);
+        assert!(result.is_err(), "Existing target in the output directory should be refused");
+        assert!(encrypted_path.exists());
+    }
 
-        let decrypted_files = decrypt_folder(temp_dir.path(), password).unwrap();
-        assert_eq!(decrypted_files.len(), 2);
-        for encrypted_file in &encrypted_files {
-            assert!(!encrypted_file.exists(), "Encrypted files should be deleted after decryption");
+    #[test]
+    fn test_conflict_policies_on_decrypt() {
+        let temp_dir = TempDir::new().unwrap();
+        let test_file = temp_dir.path().join("test.txt");
+        let password = &Password::from("test_password");
+
+        let encrypt_options = EncryptOptions { keep: true, ..EncryptOptions::default() };
+        fs::write(&test_file, b"Encrypted").unwrap();
+        let encrypted_path = encrypt_file(&test_file, password, "Test question", &encrypt_options).unwrap().into_path();
+        fs::write(&test_file, b"Existing").unwrap();
+
+        let decrypt_options = |on_conflict| DecryptOptions {
+            keep_encrypted: true,
+            output: OutputOptions { on_conflict, ..OutputOptions::default() },
+            ..DecryptOptions::default()
+        };
+
+        assert!(decrypt_file(&encrypted_path, password, &decrypt_options(ConflictPolicy::Error)).is_err());
+
+        let outcome = decrypt_file(&encrypted_path, password, &decrypt_options(ConflictPolicy::Skip)).unwrap();
+        assert_eq!(outcome, Outcome::Skipped(test_file.clone()));
+        assert_eq!(fs::read(&test_file).unwrap(), b"Existing");
+
+        let outcome = decrypt_file(&encrypted_path, password, &decrypt_options(ConflictPolicy::Rename)).unwrap();
+        assert_eq!(outcome, Outcome::Written(temp_dir.path().join("test (1).txt")));
+        assert_eq!(fs::read(outcome.path()).unwrap(), b"Encrypted");
+        assert_eq!(fs::read(&test_file).unwrap(), b"Existing");
+
+        let outcome = decrypt_file(&encrypted_path, password, &decrypt_options(ConflictPolicy::Overwrite)).unwrap();
+        assert_eq!(outcome, Outcome::Written(test_file.clone()));
+        assert_eq!(fs::read(&test_file).unwrap(), b"Encrypted");
+    }
+
+    #[test]
+    fn test_preserves_attributes() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("build.sh");
+        fs::write(&script, b"#!/bin/sh\necho hi\n").unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        fs::File::options().write(true).open(&script).unwrap()
+            .set_modified(mtime).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
         }
+
+        let password = &Password::from("test_password");
+        let encrypt_options = EncryptOptions { keep: true, ..EncryptOptions::default() };
+        let encrypted_path = encrypt_file(&script, password, "Test question", &encrypt_options).unwrap().into_path();
+        fs::remove_file(&script).unwrap();
+
+        let decrypt_options = DecryptOptions { keep_encrypted: true, ..DecryptOptions::default() };
+        decrypt_file(&encrypted_path, password, &decrypt_options).unwrap();
+        let restored = fs::metadata(&script).unwrap();
+        assert_eq!(restored.modified().unwrap(), mtime);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(restored.permissions().mode() & 0o7777, 0o755);
+        }
+
+        fs::remove_file(&script).unwrap();
+        let decrypt_options = DecryptOptions { preserve: false, ..DecryptOptions::default() };
+        decrypt_file(&encrypted_path, password, &decrypt_options).unwrap();
+        assert_ne!(fs::metadata(&script).unwrap().modified().unwrap(), mtime);
+    }
+
+    #[test]
+    fn test_large_file_round_trip_in_segments() {
+        let password = Password::from("test_password");
+        let temp_dir = TempDir::new().unwrap();
+        let large = temp_dir.path().join("large.bin");
+        let data: Vec<u8> = (0..crate::segments::SEGMENT_SIZE * 2 + 1000).map(|i| (i % 253) as u8).collect();
+        fs::write(&large, &data).unwrap();
+
+        let encrypt_options = EncryptOptions { jobs: 4, ..EncryptOptions::default() };
+        let encrypted_path = encrypt_file(&large, &password, "Test question", &encrypt_options).unwrap().into_path();
+        assert_eq!(read_header(&encrypted_path).unwrap().version, VERSION);
+
+        let decrypt_options = DecryptOptions { jobs: 4, ..DecryptOptions::default() };
+        assert_eq!(decrypt_file(&encrypted_path, &Password::from("wrong_password"), &decrypt_options).unwrap_err(), DecryptError::WrongPassword);
+        decrypt_file(&encrypted_path, &password, &decrypt_options).unwrap();
+        assert_eq!(fs::read(&large).unwrap(), data);
+    }
+
+    #[test]
+    fn test_read_header_and_retry_on_open_file() {
+        let password = Password::from("test_password");
+        let temp_dir = TempDir::new().unwrap();
+        let test_file = temp_dir.path().join("test.txt");
+        fs::write(&test_file, b"Secret content").unwrap();
+
+        let encrypted_path = encrypt_file(&test_file, &password, "Test question", &EncryptOptions::default()).unwrap().into_path();
+        let metadata = read_header(&encrypted_path).unwrap();
+        assert_eq!(metadata.helper_question, "Test question");
+        assert_eq!(metadata.original_name, "test.txt");
+
+        let mut file = EncryptedFile::open(&encrypted_path).unwrap();
+        let error = decrypt_opened_file(&mut file, &Password::from("wrong_password"), &DecryptOptions::default()).unwrap_err();
+        assert_eq!(error, DecryptError::WrongPassword);
+        decrypt_opened_file(&mut file, &password, &DecryptOptions::default()).unwrap();
+        assert_eq!(fs::read(&test_file).unwrap(), b"Secret content");
+    }
+
+    #[test]
+    fn test_reads_golden_files_of_every_version() {
+        let password = Password::from("golden");
+        let temp_dir = TempDir::new().unwrap();
+
+        for version in MIN_VERSION..=VERSION {
+            let dir = temp_dir.path().join(format!("v{}", version));
+            fs::create_dir(&dir).unwrap();
+            let options = DecryptOptions { keep_encrypted: true, output: OutputOptions { dir: Some(dir.clone()), ..OutputOptions::default() }, ..DecryptOptions::default() };
+
+            let mut file = EncryptedFile::open(&golden(version, "hello.txt.ect")).unwrap();
+            assert_eq!(file.metadata.version, version);
+            assert_eq!(file.metadata.helper_question, "Favourite test?");
+            decrypt_opened_file(&mut file, &password, &options).unwrap();
+            assert_eq!(fs::read(dir.join("hello.txt")).unwrap(), b"golden file\n");
+            #[cfg(unix)]
+            if version >= 2 {
+                use std::os::unix::fs::PermissionsExt;
+                assert_eq!(fs::metadata(dir.join("hello.txt")).unwrap().permissions().mode() & 0o777, 0o640);
+            }
+
+            decrypt_folder_archive(&golden(version, "notes.ect"), &password, &options).unwrap();
+            assert_eq!(fs::read(dir.join("notes/a.txt")).unwrap(), b"first\n");
+            assert_eq!(fs::read(dir.join("notes/sub/b.txt")).unwrap(), b"second\n");
+        }
+    }
+
+    #[test]
+    fn test_lists_golden_files() {
+        let password = Password::from("golden");
+        for version in [MIN_VERSION, VERSION] {
+            let mut file = EncryptedFile::open(&golden(version, "hello.txt.ect")).unwrap();
+            assert_eq!(list_opened_file(&mut file, &Password::from("wrong"), 1).unwrap_err(), DecryptError::WrongPassword);
+            let listed = list_opened_file(&mut file, &password, 1).unwrap();
+            assert_eq!(listed, [ListedEntry { path: PathBuf::from("hello.txt"), kind: ListedKind::File, size: 12 }]);
+
+            let mut file = EncryptedFile::open(&golden(version, "notes.ect")).unwrap();
+            let mut listed = list_opened_file(&mut file, &password, 1).unwrap();
+            listed.sort_by(|a, b| a.path.cmp(&b.path));
+            let files: Vec<(PathBuf, u64)> = listed.into_iter()
+                .filter(|entry| entry.kind == ListedKind::File)
+                .map(|entry| (entry.path, entry.size))
+                .collect();
+            assert_eq!(files, [(PathBuf::from("a.txt"), 6), (PathBuf::from("sub/b.txt"), 7)]);
+        }
+    }
+
+    #[test]
+    fn test_rejects_traversal_in_original_name() {
+        let temp_dir = TempDir::new().unwrap();
+        let encrypted_path = temp_dir.path().join("evil.ect");
+        let metadata = Metadata::new([0u8; 12], [0u8; 32], "Test question".to_string(), "../../.bashrc".to_string(), ContentType::File);
+        stage_encrypted_file(&encrypted_path, &metadata, false, |file| {
+            file.write_all(b"ciphertext").map_err(|e| e.to_string())
+        }).unwrap().commit().unwrap();
+
+        let error = decrypt_file(&encrypted_path, &Password::from("test_password"), &DecryptOptions::default()).unwrap_err().to_string();
+        assert!(error.contains("path separator"), "{}", error);
+        assert!(encrypted_path.exists());
+    }
+
+    #[test]
+    fn test_failed_verification_keeps_previous_output() {
+        let temp_dir = TempDir::new().unwrap();
+        let output = temp_dir.path().join("out.ect");
+        fs::write(&output, b"previous").unwrap();
+
+        let staged = stage_file(&output, true, |file| file.write_all(b"new")).unwrap();
+        let error = verify_and_commit(staged, |_| Err("mismatch".to_string())).unwrap_err();
+        assert_eq!(error, "Verification failed, original kept: mismatch");
+        assert_eq!(fs::read(&output).unwrap(), b"previous");
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1, "Staged output should be removed");
+
+        let staged = stage_file(&output, true, |file| file.write_all(b"new")).unwrap();
+        verify_and_commit(staged, |staged_path| {
+            assert_eq!(fs::read(staged_path).unwrap(), b"new");
+            Ok(())
+        }).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"new");
     }
 
     #[test]
@@ -341,12 +1097,12 @@ mod tests {
         let test_file = temp_dir.path().join("test.txt");
         fs::write(&test_file, b"Secret content").unwrap();
 
-        let password = "correct_password";
-        let wrong_password = "wrong_password";
+        let password = &Password::from("correct_password");
+        let wrong_password = &Password::from("wrong_password");
         let helper_question = "Test question";
 
-        let encrypted_path = encrypt_file(&test_file, password, helper_question).unwrap();
-        let result = decrypt_file(&encrypted_path, wrong_password);
+        let encrypted_path = encrypt_file(&test_file, password, helper_question, &EncryptOptions::default()).unwrap().into_path();
+        let result = decrypt_file(&encrypted_path, wrong_password, &DecryptOptions::default());
 
         assert!(result.is_err());
     }
@@ -0,0 +1,399 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use ignore::Match;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use walkdir::{DirEntry, WalkDir};
+
+/// Which entries of a folder go into its archive. An empty filter keeps
+/// everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FolderFilter {
+    /// Globs for entries to leave out; an excluded directory is skipped whole.
+    pub exclude: Vec<String>,
+    /// If set, only files matching one of these globs (or inside a matching
+    /// directory) are kept. Directories are always walked.
+    pub include: Vec<String>,
+    pub respect_gitignore: bool,
+    /// Do not descend into other mounted filesystems.
+    pub one_file_system: bool,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<SystemTime>,
+    pub modified_before: Option<SystemTime>,
+}
+
+/// Globs without a `/` match an entry's name at any depth; globs with one
+/// match its path relative to the folder, like `.gitignore` patterns.
+struct PatternSet {
+    by_name: GlobSet,
+    by_path: GlobSet,
+}
+
+impl PatternSet {
+    fn new(patterns: &[String]) -> Result<Self, String> {
+        let mut by_name = GlobSetBuilder::new();
+        let mut by_path = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            let trimmed = pattern.trim_end_matches('/');
+            let (target, glob) = match trimmed.strip_prefix('/') {
+                Some(anchored) => (&mut by_path, anchored),
+                None if trimmed.contains('/') => (&mut by_path, trimmed),
+                None => (&mut by_name, trimmed),
+            };
+            let glob = GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| format!("Invalid pattern {:?}: {}", pattern, e))?;
+            target.add(glob);
+        }
+
+        Ok(Self {
+            by_name: by_name.build().map_err(|e| format!("Invalid patterns: {}", e))?,
+            by_path: by_path.build().map_err(|e| format!("Invalid patterns: {}", e))?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by_name.is_empty() && self.by_path.is_empty()
+    }
+
+    fn matches(&self, relative_path: &Path) -> bool {
+        relative_path.file_name().is_some_and(|name| self.by_name.is_match(name))
+            || self.by_path.is_match(relative_path)
+    }
+
+    /// Whether the path or one of the directories it is in matches.
+    fn matches_with_parents(&self, relative_path: &Path) -> bool {
+        relative_path.ancestors()
+            .take_while(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| self.matches(ancestor))
+    }
+}
+
+impl FolderFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// One line per active filter, as recorded in the archive.
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        lines.extend(self.exclude.iter().map(|pattern| format!("exclude {}", pattern)));
+        lines.extend(self.include.iter().map(|pattern| format!("include {}", pattern)));
+        if self.respect_gitignore {
+            lines.push("respect-gitignore".to_string());
+        }
+        if self.one_file_system {
+            lines.push("one-file-system".to_string());
+        }
+        if let Some(size) = self.min_size {
+            lines.push(format!("min-size {}", size));
+        }
+        if let Some(size) = self.max_size {
+            lines.push(format!("max-size {}", size));
+        }
+        if let Some(time) = self.modified_after {
+            lines.push(format!("modified-after {}", unix_seconds(time)));
+        }
+        if let Some(time) = self.modified_before {
+            lines.push(format!("modified-before {}", unix_seconds(time)));
+        }
+        lines
+    }
+
+    pub fn walker(&self, root: &Path, follow_symlinks: bool) -> Result<FolderWalker, String> {
+        Ok(FolderWalker {
+            root: root.to_path_buf(),
+            follow_symlinks,
+            filter: self.clone(),
+            exclude: PatternSet::new(&self.exclude)?,
+            include: PatternSet::new(&self.include)?,
+            gitignores: Vec::new(),
+            excluded: 0,
+        })
+    }
+}
+
+/// Walks a folder applying a [`FolderFilter`] and counts what it left out.
+pub struct FolderWalker {
+    root: PathBuf,
+    follow_symlinks: bool,
+    filter: FolderFilter,
+    exclude: PatternSet,
+    include: PatternSet,
+    /// `.gitignore` files of the directories above the current entry, with
+    /// the depth of the directory they belong to.
+    gitignores: Vec<(usize, Gitignore)>,
+    excluded: usize,
+}
+
+impl FolderWalker {
+    pub fn entries(&mut self) -> impl Iterator<Item = walkdir::Result<DirEntry>> + '_ {
+        WalkDir::new(&self.root)
+            .follow_links(self.follow_symlinks)
+            .same_file_system(self.filter.one_file_system)
+            .into_iter()
+            .filter_entry(|entry| {
+                let accepted = self.accepts(entry);
+                if !accepted {
+                    self.excluded += 1;
+                }
+                accepted
+            })
+    }
+
+    /// How many entries the filter left out; an excluded directory counts once.
+    pub fn excluded(&self) -> usize {
+        self.excluded
+    }
+
+    fn accepts(&mut self, entry: &DirEntry) -> bool {
+        let depth = entry.depth();
+        if depth == 0 {
+            self.load_gitignore(entry);
+            return true;
+        }
+
+        let Ok(relative_path) = entry.path().strip_prefix(&self.root) else {
+            return true;
+        };
+        let is_dir = entry.file_type().is_dir();
+
+        if self.exclude.matches(relative_path) || self.gitignored(entry.path(), depth, is_dir) {
+            return false;
+        }
+
+        if is_dir {
+            self.load_gitignore(entry);
+            return true;
+        }
+
+        if !self.include.is_empty() && !self.include.matches_with_parents(relative_path) {
+            return false;
+        }
+
+        !entry.file_type().is_file() || self.accepts_metadata(entry)
+    }
+
+    fn gitignored(&mut self, path: &Path, depth: usize, is_dir: bool) -> bool {
+        // Drop the .gitignore files of directories we have walked out of.
+        while self.gitignores.last().is_some_and(|(dir_depth, _)| *dir_depth >= depth) {
+            self.gitignores.pop();
+        }
+
+        for (_, gitignore) in self.gitignores.iter().rev() {
+            match gitignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    fn load_gitignore(&mut self, entry: &DirEntry) {
+        if !self.filter.respect_gitignore {
+            return;
+        }
+
+        let gitignore_path = entry.path().join(".gitignore");
+        if gitignore_path.is_file() {
+            // Like git, patterns that fail to parse are ignored.
+            let (gitignore, _) = Gitignore::new(&gitignore_path);
+            self.gitignores.push((entry.depth(), gitignore));
+        }
+    }
+
+    fn accepts_metadata(&self, entry: &DirEntry) -> bool {
+        let filter = &self.filter;
+        if filter.min_size.is_none() && filter.max_size.is_none()
+            && filter.modified_after.is_none() && filter.modified_before.is_none()
+        {
+            return true;
+        }
+
+        // Leave unreadable entries for the archive walk to report.
+        let Ok(metadata) = entry.metadata() else {
+            return true;
+        };
+
+        let size = metadata.len();
+        if filter.min_size.is_some_and(|min| size < min) || filter.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+
+        match metadata.modified() {
+            Ok(modified) => {
+                filter.modified_after.is_none_or(|after| modified >= after)
+                    && filter.modified_before.is_none_or(|before| modified < before)
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// Parses a size such as `500`, `64K`, `10M` or `2G` (powers of 1024).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => {
+            let multiplier = match unit.to_ascii_uppercase() {
+                'B' => 1,
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(format!("Unknown size unit in {:?}", value)),
+            };
+            (&value[..index], multiplier)
+        }
+        _ => (value, 1),
+    };
+
+    digits.trim().parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size: {:?}", value))
+}
+
+/// Length of `month` in `year` of the proleptic Gregorian calendar.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Parses `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`, in UTC.
+pub fn parse_time(value: &str) -> Result<SystemTime, String> {
+    let invalid = || format!("Invalid date {:?}: expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS", value);
+    let (date, time) = value.trim().split_once(['T', ' ']).unwrap_or((value.trim(), "00:00:00"));
+
+    let date: Vec<i64> = date.split('-').map(|part| part.parse().map_err(|_| invalid())).collect::<Result<_, _>>()?;
+    let time: Vec<i64> = time.split(':').map(|part| part.parse().map_err(|_| invalid())).collect::<Result<_, _>>()?;
+    let (&[year, month, day], &[hour, minute, second]) = (date.as_slice(), time.as_slice()) else {
+        return Err(invalid());
+    };
+    // Four-digit years keep `days_from_civil` far from overflowing.
+    if !(0..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23 || minute > 59 || second > 59 || hour < 0 || minute < 0 || second < 0
+    {
+        return Err(invalid());
+    }
+
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second;
+    Ok(if seconds >= 0 {
+        UNIX_EPOCH + Duration::from_secs(seconds as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn walk(root: &Path, filter: &FolderFilter) -> (BTreeSet<String>, usize) {
+        let mut walker = filter.walker(root, false).unwrap();
+        let paths = walker.entries()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.depth() > 0)
+            .map(|entry| entry.path().strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect();
+        (paths, walker.excluded())
+    }
+
+    #[test]
+    fn test_exclude_include_and_gitignore() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("src").join("target")).unwrap();
+        fs::create_dir_all(root.join("target").join("debug")).unwrap();
+        fs::create_dir_all(root.join("logs")).unwrap();
+        fs::write(root.join("src").join("main.rs"), b"fn main() {}").unwrap();
+        fs::write(root.join("src").join("target").join("notes.md"), b"notes").unwrap();
+        fs::write(root.join("target").join("debug").join("app"), b"binary").unwrap();
+        fs::write(root.join("logs").join("today.log"), b"log").unwrap();
+        fs::write(root.join("README.md"), b"readme").unwrap();
+        fs::write(root.join(".gitignore"), b"*.log\n").unwrap();
+
+        let filter = FolderFilter { exclude: vec!["/target/".to_string()], ..FolderFilter::default() };
+        let (paths, excluded) = walk(root, &filter);
+        assert!(!paths.contains("target") && paths.contains("src/target/notes.md"), "{:?}", paths);
+        assert_eq!(excluded, 1);
+
+        let filter = FolderFilter { exclude: vec!["target".to_string()], respect_gitignore: true, ..FolderFilter::default() };
+        let (paths, _) = walk(root, &filter);
+        assert!(!paths.contains("src/target") && !paths.contains("logs/today.log"), "{:?}", paths);
+        assert!(paths.contains("logs") && paths.contains("src/main.rs"), "{:?}", paths);
+
+        let filter = FolderFilter { include: vec!["*.rs".to_string(), "logs".to_string()], ..FolderFilter::default() };
+        let (paths, _) = walk(root, &filter);
+        assert!(paths.contains("src/main.rs") && paths.contains("logs/today.log"), "{:?}", paths);
+        assert!(!paths.contains("README.md") && !paths.contains("src/target/notes.md"), "{:?}", paths);
+    }
+
+    #[test]
+    fn test_size_and_time_filters() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("small"), b"1").unwrap();
+        fs::write(root.join("large"), vec![0u8; 4096]).unwrap();
+        fs::File::options().write(true).open(root.join("small")).unwrap()
+            .set_modified(parse_time("2001-09-09T01:46:40").unwrap()).unwrap();
+
+        let filter = FolderFilter { max_size: Some(parse_size("1K").unwrap()), ..FolderFilter::default() };
+        assert_eq!(walk(root, &filter).0, BTreeSet::from(["small".to_string()]));
+
+        let filter = FolderFilter { modified_after: Some(parse_time("2020-01-01").unwrap()), ..FolderFilter::default() };
+        assert_eq!(walk(root, &filter).0, BTreeSet::from(["large".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_size_and_time() {
+        assert_eq!(parse_size("500").unwrap(), 500);
+        assert_eq!(parse_size("64k").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("10M").unwrap(), 10 * 1024 * 1024);
+        assert!(parse_size("10Q").is_err());
+        assert!(parse_size("").is_err());
+
+        assert_eq!(parse_time("1970-01-01").unwrap(), UNIX_EPOCH);
+        assert_eq!(parse_time("2001-09-09T01:46:40").unwrap(), UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+        assert_eq!(parse_time("1969-12-31").unwrap(), UNIX_EPOCH - Duration::from_secs(86_400));
+        assert!(parse_time("2024-13-01").is_err());
+        assert_eq!(parse_time("2024-02-29").unwrap(), parse_time("2024-03-01").unwrap() - Duration::from_secs(86_400));
+        assert!(parse_time("2024-02-30").is_err());
+        assert!(parse_time("2024-02-31").is_err());
+        assert!(parse_time("2023-02-29").is_err());
+        assert!(parse_time("2000-02-29").is_ok());
+        assert!(parse_time("1900-02-29").is_err());
+        assert!(parse_time("2023-04-31").is_err());
+        assert!(parse_time("99999999999999999-01-01").is_err());
+        assert!(parse_time("10000-01-01").is_err());
+        assert!(parse_time("yesterday").is_err());
+    }
+}
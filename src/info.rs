@@ -0,0 +1,165 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::file_ops::EncryptedFile;
+use crate::metadata::{ContentType, KeyDerivation, Metadata};
+use crate::segments::SEGMENT_SIZE;
+
+/// What the header of an encrypted file says about it. Reading it needs no
+/// password, and nothing of the payload is decrypted. Serializes to the
+/// object `info --json` prints.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub format_version: u8,
+    pub cipher: CipherInfo,
+    pub kdf: KdfInfo,
+    pub content_type: &'static str,
+    pub original_name: String,
+    /// Whether the original name is kept out of the readable header. No
+    /// format version does this yet.
+    pub name_hidden: bool,
+    pub helper_question: String,
+    /// Size of the encrypted payload after the header.
+    pub ciphertext_size: u64,
+    /// Ways to unlock the file. Every format version has exactly one: the
+    /// password.
+    pub key_slots: Vec<KeySlot>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CipherInfo {
+    pub algorithm: &'static str,
+    /// Plaintext bytes per authenticated segment, or `None` for payloads
+    /// encrypted as one message (format version 1).
+    pub segment_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KdfInfo {
+    pub algorithm: &'static str,
+    pub iterations: u32,
+    /// Hex-encoded PBKDF2 salt.
+    pub salt: String,
+    /// Set when the PBKDF2 output is a master key shared by the files
+    /// encrypted together, which HKDF turns into this file's key.
+    pub subkey: Option<SubkeyInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubkeyInfo {
+    pub algorithm: &'static str,
+    /// Hex-encoded per-file HKDF salt.
+    pub file_salt: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KeySlot {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl FileInfo {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let file = EncryptedFile::open(path)?;
+        let ciphertext_size = file.payload_len()?;
+        Ok(Self::from_metadata(path, &file.metadata, ciphertext_size))
+    }
+
+    fn from_metadata(path: &Path, metadata: &Metadata, ciphertext_size: u64) -> Self {
+        let subkey = match metadata.key_derivation {
+            KeyDerivation::Pbkdf2 => None,
+            KeyDerivation::Pbkdf2Hkdf { file_salt } => Some(SubkeyInfo { algorithm: "HKDF-SHA256", file_salt: hex(&file_salt) }),
+        };
+
+        Self {
+            path: path.to_path_buf(),
+            format_version: metadata.version,
+            cipher: CipherInfo {
+                algorithm: "AES-256-GCM",
+                segment_size: (metadata.version >= 2).then_some(SEGMENT_SIZE),
+            },
+            kdf: KdfInfo {
+                algorithm: "PBKDF2-HMAC-SHA256",
+                iterations: metadata.kdf_iterations,
+                salt: hex(&metadata.salt),
+                subkey,
+            },
+            content_type: match metadata.content_type {
+                ContentType::File => "file",
+                ContentType::Folder => "folder",
+            },
+            original_name: metadata.original_name.clone(),
+            name_hidden: false,
+            helper_question: metadata.helper_question.clone(),
+            ciphertext_size,
+            key_slots: vec![KeySlot { kind: "password" }],
+        }
+    }
+
+    /// Labelled values in the order `info` prints them.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let cipher = match self.cipher.segment_size {
+            Some(size) => format!("{} in {} MiB segments", self.cipher.algorithm, size >> 20),
+            None => format!("{}, one message", self.cipher.algorithm),
+        };
+        let mut kdf = format!("{}, {} iterations", self.kdf.algorithm, self.kdf.iterations);
+        if let Some(subkey) = &self.kdf.subkey {
+            kdf.push_str(&format!(", then {} with a per-file salt", subkey.algorithm));
+        }
+        let slots: Vec<&str> = self.key_slots.iter().map(|slot| slot.kind).collect();
+
+        vec![
+            ("Format version", self.format_version.to_string()),
+            ("Cipher", cipher),
+            ("Key derivation", kdf),
+            ("Content", self.content_type.to_string()),
+            ("Original name", if self.name_hidden { "(hidden)".to_string() } else { self.original_name.clone() }),
+            ("Helper question", self.helper_question.clone()),
+            ("Encrypted size", format!("{} bytes", self.ciphertext_size)),
+            ("Key slots", format!("{} ({})", slots.len(), slots.join(", "))),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{golden, VERSION};
+
+    #[test]
+    fn test_reads_golden_headers() {
+        let info = FileInfo::read(&golden(1, "hello.txt.ect")).unwrap();
+        assert_eq!(info.fields(), [
+            ("Format version", "1".to_string()),
+            ("Cipher", "AES-256-GCM, one message".to_string()),
+            ("Key derivation", "PBKDF2-HMAC-SHA256, 100000 iterations".to_string()),
+            ("Content", "file".to_string()),
+            ("Original name", "hello.txt".to_string()),
+            ("Helper question", "Favourite test?".to_string()),
+            // "golden file\n" and its 16-byte tag.
+            ("Encrypted size", "28 bytes".to_string()),
+            ("Key slots", "1 (password)".to_string()),
+        ]);
+
+        let info = FileInfo::read(&golden(VERSION, "notes.ect")).unwrap();
+        assert_eq!(info.fields()[1], ("Cipher", "AES-256-GCM in 1 MiB segments".to_string()));
+        assert_eq!(info.fields()[3], ("Content", "folder".to_string()));
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["format_version"], VERSION);
+        assert_eq!(json["content_type"], "folder");
+        assert_eq!(json["name_hidden"], false);
+        assert_eq!(json["kdf"]["iterations"], 100_000);
+        assert_eq!(json["kdf"]["salt"].as_str().unwrap().len(), 64);
+        assert_eq!(json["cipher"]["segment_size"], 1 << 20);
+        assert_eq!(json["key_slots"][0]["type"], "password");
+        assert_eq!(json["ciphertext_size"], info.ciphertext_size);
+
+        assert!(FileInfo::read(&golden(VERSION, "missing.ect")).is_err());
+    }
+}
@@ -0,0 +1,21 @@
+pub mod archive;
+pub mod attributes;
+pub mod atomic;
+pub mod batch;
+pub mod cli;
+pub mod config;
+pub mod conflict;
+pub mod encryption;
+pub mod file_ops;
+pub mod filter;
+pub mod info;
+pub mod metadata;
+pub mod password;
+pub mod pool;
+pub mod segments;
+pub mod selection;
+pub mod session;
+pub mod tlv;
+pub mod upgrade;
+pub mod verify;
+
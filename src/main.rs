@@ -1,129 +1,656 @@
-mod archive;
-mod cli;
-mod encryption;
-mod file_ops;
-mod metadata;
-mod password;
-mod selection;
-
 use std::io::{self, Write};
-use std::path::Path;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 
-use cli::Cli;
-use file_ops::{decrypt_file, decrypt_folder_archive, encrypt_file, encrypt_folder_archive, is_encrypted_file, read_encrypted_file};
-use metadata::ContentType;
-use password::{get_password, get_password_with_confirmation};
-use selection::select_files_interactive;
+use ectfy::archive::{format_unreadable, ArchiveOptions, ExtendedAttributes, ListedEntry, ListedKind};
+use ectfy::batch::{batch_encrypt_options, check_collisions, expand_encrypted_inputs, expand_inputs, plan_outputs, read_path_list, BatchItem, Mode};
+use ectfy::cli::{BatchArgs, Cli, Command, DecryptArgs, EncryptArgs, ExtendedArgs, RewriteArgs};
+use ectfy::config::Config;
+use ectfy::filter::FolderFilter;
+use ectfy::encryption::{generate_salt, Password};
+use ectfy::file_ops::{
+    decrypt_opened_file, decrypt_opened_folder_archive, encrypt_file, encrypt_folder_archive, list_opened_file,
+    read_header, DecryptError, DecryptOptions, EncryptOptions, EncryptedFile, Outcome, OutputOptions,
+};
+use ectfy::info::FileInfo;
+use ectfy::metadata::ContentType;
+use ectfy::password::{generate_password, get_password_with_confirmation, mark_stdin_consumed, read_line};
+use ectfy::pool::{default_jobs, jobs_per_item, run_parallel};
+use ectfy::selection::select_files_interactive;
+use ectfy::session::Session;
+use ectfy::upgrade::{rekey_opened_file, upgrade_opened_file, UpgradeOptions};
+use ectfy::verify::authenticate_opened_file;
 
 fn prompt_helper_question() -> io::Result<String> {
     print!("Enter helper question for decryption: ");
     io::stdout().flush()?;
     let mut question = String::new();
-    io::stdin().read_line(&mut question)?;
+    read_line(&mut question)?;
     Ok(question.trim().to_string())
 }
 
-fn process_path(path: &Path, show_password: bool) -> Result<(), String> {
-    if is_encrypted_file(path) {
-        let (metadata, _) = read_encrypted_file(path)
-            .map_err(|e| format!("Failed to read encrypted file: {}", e))?;
-        
-        println!("Helper question: {}", metadata.helper_question);
-        
-        let password = get_password(show_password)
-            .map_err(|e| format!("Failed to read password: {}", e))?;
-
-        match metadata.content_type {
-            ContentType::File => {
-                let decrypted = decrypt_file(path, &password)?;
-                println!("✓ Decrypted {} → {}", path.display(), decrypted.display());
+/// Asks for a new password and helper question for encrypting.
+fn prompt_encryption_secrets(show_password: bool) -> Result<(Password, String), String> {
+    let password = get_password_with_confirmation(show_password)
+        .map(Password::new)
+        .map_err(|e| format!("Failed to read password: {}", e))?;
+
+    let helper_question = prompt_helper_question()
+        .map_err(|e| format!("Failed to read helper question: {}", e))?;
+
+    if helper_question.is_empty() {
+        return Err("Helper question cannot be empty".to_string());
+    }
+
+    Ok((password, helper_question))
+}
+
+fn print_outcome(verb: &str, input: &Path, outcome: &Outcome) {
+    match outcome {
+        Outcome::Written(output) => println!("✓ {} {} → {}", verb, input.display(), output.display()),
+        Outcome::Skipped(existing) => println!("↷ Skipped {}: {} already exists", input.display(), existing.display()),
+        Outcome::Incomplete(output, report) => {
+            println!("⚠ {} {} → {}; the original was kept because some entries were left out:", verb, input.display(), output.display());
+            if report.excluded > 0 {
+                println!("  {} entr{} excluded by filters", report.excluded, if report.excluded == 1 { "y" } else { "ies" });
             }
-            ContentType::Folder => {
-                let decrypted = decrypt_folder_archive(path, &password)?;
-                println!("✓ Decrypted {} → {}", path.display(), decrypted.display());
+            if !report.unreadable.is_empty() {
+                println!("  {}", format_unreadable(&report.unreadable));
             }
         }
+    }
+}
+
+fn encrypt_path(path: &Path, password: &Password, helper_question: &str, options: &EncryptOptions) -> Result<Outcome, String> {
+    if path.is_file() {
+        encrypt_file(path, password, helper_question, options)
+    } else if path.is_dir() {
+        encrypt_folder_archive(path, password, helper_question, options)
     } else {
-        let password = get_password_with_confirmation(show_password)
-            .map_err(|e| format!("Failed to read password: {}", e))?;
+        Err(format!("Path does not exist: {}", path.display()))
+    }
+}
+
+fn decrypt_opened(file: &mut EncryptedFile, password: &Password, options: &DecryptOptions) -> Result<Outcome, DecryptError> {
+    match file.metadata.content_type {
+        ContentType::File => decrypt_opened_file(file, password, options),
+        ContentType::Folder => decrypt_opened_folder_archive(file, password, options),
+    }
+}
+
+/// Opens an encrypted input once and runs `action` on it with the passwords
+/// `try_passwords` picks; it gets the helper question and a way to attempt
+/// one password.
+fn open_encrypted_input<T, A, F>(path: &Path, mut action: A, try_passwords: F) -> Result<T, DecryptError>
+where
+    A: FnMut(&mut EncryptedFile, &Password) -> Result<T, DecryptError>,
+    F: FnOnce(&str, &mut dyn FnMut(&Password) -> Result<T, DecryptError>) -> Result<T, DecryptError>,
+{
+    let mut file = EncryptedFile::open(path)
+        .map_err(|e| format!("Failed to read encrypted file: {}", e))?;
+    let helper_question = file.metadata.helper_question.clone();
+    try_passwords(&helper_question, &mut |password| action(&mut file, password))
+}
+
+/// Counts one more file for `helper_question`, keeping questions in order of
+/// first appearance.
+fn count_question(questions: &mut Vec<(String, usize)>, helper_question: String) {
+    match questions.iter_mut().find(|(question, _)| *question == helper_question) {
+        Some((_, count)) => *count += 1,
+        None => questions.push((helper_question, 1)),
+    }
+}
+
+/// Asks for one password per distinct helper question of `files`, in order
+/// of first appearance. Files whose header can't be read are left out; they
+/// fail on their own later.
+fn prompt_passwords(files: &[&Path], show_password: bool) -> Result<Session, String> {
+    let mut questions: Vec<(String, usize)> = Vec::new();
+    for path in files {
+        if let Ok(metadata) = read_header(path) {
+            count_question(&mut questions, metadata.helper_question);
+        }
+    }
+
+    let mut session = Session::new(show_password);
+    for (question, count) in &questions {
+        session.prompt(question, *count)?;
+    }
+    Ok(session)
+}
 
-        let helper_question = prompt_helper_question()
-            .map_err(|e| format!("Failed to read helper question: {}", e))?;
+/// Runs `action` on every file in `files`, up to `jobs` at once, with the
+/// passwords in `session`. Files that none of them opens ask for another one
+/// when their turn comes to be handled, in input order.
+fn run_with_passwords<T, A, H>(files: &[PathBuf], mut session: Session, jobs: usize, action: A, mut handle: H)
+where
+    T: Send,
+    A: Fn(&mut EncryptedFile, &Password) -> Result<T, DecryptError> + Sync,
+    H: FnMut(&Path, Result<T, DecryptError>),
+{
+    let known = session.clone();
+    run_parallel(files, jobs, |path| {
+        open_encrypted_input(path, &action, |helper_question, attempt| known.try_known(helper_question, attempt))
+    }, |path, result| {
+        let result = match result {
+            Err(DecryptError::WrongPassword) => {
+                open_encrypted_input(path, &action, |helper_question, attempt| session.decrypt(path, helper_question, attempt))
+            }
+            result => result,
+        };
+        handle(path, result);
+    });
+}
 
-        if helper_question.is_empty() {
-            return Err("Helper question cannot be empty".to_string());
+/// Counts what happened to the inputs of a batch.
+#[derive(Debug, Default)]
+struct Summary {
+    encrypted: usize,
+    decrypted: usize,
+    upgraded: usize,
+    rekeyed: usize,
+    up_to_date: usize,
+    verified: usize,
+    listed: usize,
+    skipped: usize,
+    incomplete: usize,
+    failed: usize,
+}
+
+impl Summary {
+    fn record(&mut self, decrypted: bool, outcome: &Outcome) {
+        match outcome {
+            Outcome::Skipped(_) => self.skipped += 1,
+            Outcome::Incomplete(..) => self.incomplete += 1,
+            Outcome::Written(_) if decrypted => self.decrypted += 1,
+            Outcome::Written(_) => self.encrypted += 1,
         }
+    }
+
+    fn total(&self) -> usize {
+        self.encrypted + self.decrypted + self.upgraded + self.rekeyed + self.up_to_date + self.verified + self.listed
+            + self.skipped + self.incomplete + self.failed
+    }
 
-        if path.is_file() {
-            let encrypted = encrypt_file(path, &password, &helper_question)?;
-            println!("✓ Encrypted {} → {}", path.display(), encrypted.display());
-        } else if path.is_dir() {
-            let encrypted = encrypt_folder_archive(path, &password, &helper_question)?;
-            println!("✓ Encrypted {} → {}", path.display(), encrypted.display());
+    fn print(&self) {
+        let counts = [
+            (self.encrypted, "encrypted"),
+            (self.decrypted, "decrypted"),
+            (self.upgraded, "upgraded"),
+            (self.rekeyed, "rekeyed"),
+            (self.up_to_date, "already up to date"),
+            (self.verified, "verified"),
+            (self.listed, "listed"),
+            (self.incomplete, "incomplete (originals kept)"),
+            (self.skipped, "skipped"),
+            (self.failed, "failed"),
+        ];
+        let parts: Vec<String> = counts.iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, label)| format!("{} {}", count, label))
+            .collect();
+
+        if parts.is_empty() {
+            println!("\nNothing was processed");
         } else {
-            return Err(format!("Path does not exist: {}", path.display()));
+            println!("\n{}: {}", if self.failed == 0 { "✓ Done" } else { "❌ Done with errors" }, parts.join(", "));
         }
     }
-
-    Ok(())
 }
 
-fn main() {
-    let cli = Cli::parse_args();
+/// One input of a batch with what it needs to be processed: plaintext
+/// inputs carry the password and helper question they are encrypted with.
+enum BatchWork<'a> {
+    Decrypt(&'a BatchItem),
+    Encrypt(&'a BatchItem, &'a (Password, String)),
+}
 
-    let result = if let Some(path) = cli.path {
-        if !path.exists() {
-            eprintln!("❌ Error: Path does not exist: {}", path.display());
-            std::process::exit(1);
+impl<'a> BatchWork<'a> {
+    fn new(item: &'a BatchItem, encryption_secrets: Option<&'a (Password, String)>) -> Result<Self, String> {
+        if item.decrypt {
+            return Ok(BatchWork::Decrypt(item));
         }
-        process_path(&path, cli.show_password)
+        encryption_secrets
+            .map(|secrets| BatchWork::Encrypt(item, secrets))
+            .ok_or_else(|| format!("No password was given to encrypt {}", item.input.display()))
+    }
+}
+
+/// Processes several inputs with as few prompts as possible: `mode` decides
+/// which inputs are decrypted, by default those with an encrypted header.
+/// The rest are encrypted with one password and helper question, and the
+/// encrypted ones decrypted with one password per helper question, asking again
+/// only for files it does not open. Nothing is touched if an input is missing
+/// or the outputs collide. With `per_file`, folders are processed file by
+/// file. Up to `jobs` items of the options are processed at once, sharing
+/// those threads with the segments of large files; results are reported in
+/// input order.
+fn run_batch(
+    paths: &[PathBuf],
+    show_password: bool,
+    per_file: bool,
+    mode: Mode,
+    encrypt_options: &EncryptOptions,
+    decrypt_options: &DecryptOptions,
+) -> Result<Summary, String> {
+    check_paths_exist(paths)?;
+
+    if paths.len() > 1 && encrypt_options.output.path.is_some() {
+        return Err("--output can only be used with a single input; use --out-dir for several".to_string());
+    }
+
+    let items = expand_inputs(paths, &encrypt_options.output, per_file, &encrypt_options.archive, mode)?;
+    for warning in items.iter().filter_map(BatchItem::mismatch_warning) {
+        println!("⚠ {}", warning);
+    }
+    let plan = plan_outputs(&items)?;
+    check_collisions(&plan, encrypt_options.output.on_conflict)?;
+
+    let encrypt_options = batch_encrypt_options(&items, encrypt_options);
+
+    let jobs = encrypt_options.output.max_jobs(encrypt_options.jobs);
+    let item_jobs = jobs_per_item(jobs, items.len());
+
+    let encryption_secrets = if items.iter().all(|item| item.decrypt) {
+        None
     } else {
-        let files = match select_files_interactive() {
-            Ok(files) => files,
-            Err(e) => {
-                eprintln!("❌ Error: {}", e);
-                std::process::exit(1);
+        Some(prompt_encryption_secrets(show_password)?)
+    };
+    let work = items.iter()
+        .map(|item| BatchWork::new(item, encryption_secrets.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let encrypted: Vec<&Path> = items.iter()
+        .filter(|item| item.decrypt)
+        .map(|item| item.input.as_path())
+        .collect();
+    let mut session = prompt_passwords(&encrypted, show_password)?;
+
+    let known = session.clone();
+
+    let mut summary = Summary::default();
+    run_parallel(&work, jobs, |work| match *work {
+        BatchWork::Decrypt(item) => {
+            let options = item.decrypt_options(decrypt_options, item_jobs);
+            open_encrypted_input(&item.input, |file, password| decrypt_opened(file, password, &options), |helper_question, attempt| {
+                known.try_known(helper_question, attempt)
+            })
+        }
+        BatchWork::Encrypt(item, (password, helper_question)) => {
+            encrypt_path(&item.input, password, helper_question, &item.encrypt_options(&encrypt_options, item_jobs)).map_err(DecryptError::Other)
+        }
+    }, |work, result| {
+        let (item, decrypt) = match *work {
+            BatchWork::Decrypt(item) => (item, true),
+            BatchWork::Encrypt(item, _) => (item, false),
+        };
+        let path = &item.input;
+
+        // Files that none of the passwords given up front open ask for
+        // another one here, in input order.
+        let result = match result {
+            Err(DecryptError::WrongPassword) if decrypt => {
+                let options = item.decrypt_options(decrypt_options, item_jobs);
+                open_encrypted_input(path, |file, password| decrypt_opened(file, password, &options), |helper_question, attempt| {
+                    session.decrypt(path, helper_question, attempt)
+                })
             }
+            result => result,
         };
 
-        if files.is_empty() {
-            println!("No files selected");
-            return;
+        match result {
+            Ok(outcome) => {
+                print_outcome(if decrypt { "Decrypted" } else { "Encrypted" }, path, &outcome);
+                summary.record(decrypt, &outcome);
+            }
+            Err(e) => {
+                eprintln!("❌ Error processing {}: {}", path.display(), e);
+                summary.failed += 1;
+            }
+        }
+    });
+
+    Ok(summary)
+}
+
+fn check_paths_exist(paths: &[PathBuf]) -> Result<(), String> {
+    match paths.iter().find(|path| path.symlink_metadata().is_err()) {
+        Some(missing) => Err(format!("Path does not exist: {}", missing.display())),
+        None => Ok(()),
+    }
+}
+
+/// Rewrites encrypted files in place. Without `rekey`, files from older
+/// format versions, or with fewer KDF iterations than asked for, are
+/// rewritten in the current format and files that are already current are
+/// left alone without asking. With `rekey`, every file is rewritten under a
+/// new password and helper question, asked for once after the old ones.
+fn run_rewrite(paths: &[PathBuf], show_password: bool, rekey: bool, options: &UpgradeOptions) -> Result<Summary, String> {
+    check_paths_exist(paths)?;
+
+    let mut summary = Summary::default();
+    let mut pending = Vec::new();
+    for path in expand_encrypted_inputs(paths)? {
+        match read_header(&path) {
+            Ok(metadata) if !rekey && options.is_current(&metadata) => {
+                println!("↷ {} is already up to date", path.display());
+                summary.up_to_date += 1;
+            }
+            Ok(_) => pending.push(path),
+            Err(e) => {
+                eprintln!("❌ Error processing {}: {}", path.display(), e);
+                summary.failed += 1;
+            }
+        }
+    }
+
+    let pending_paths: Vec<&Path> = pending.iter().map(PathBuf::as_path).collect();
+    let session = prompt_passwords(&pending_paths, show_password)?;
+    let new_secrets = if rekey && !pending.is_empty() {
+        println!("New password and helper question:");
+        Some(prompt_encryption_secrets(show_password)?)
+    } else {
+        None
+    };
+
+    // As when encrypting, several outputs share one master key.
+    let item_options = UpgradeOptions {
+        master_salt: (pending.len() > 1).then(generate_salt),
+        jobs: jobs_per_item(options.jobs, pending.len()),
+        ..options.clone()
+    };
+
+    run_with_passwords(&pending, session, options.jobs, |file, password| match &new_secrets {
+        Some((new_password, helper_question)) => rekey_opened_file(file, password, new_password, helper_question, &item_options),
+        None => upgrade_opened_file(file, password, &item_options),
+    }, |path, result| match result {
+        Ok(_) if rekey => {
+            println!("✓ Rekeyed {}", path.display());
+            summary.rekeyed += 1;
+        }
+        Ok(_) => {
+            println!("✓ Upgraded {}", path.display());
+            summary.upgraded += 1;
         }
+        Err(e) => {
+            eprintln!("❌ Error processing {}: {}", path.display(), e);
+            summary.failed += 1;
+        }
+    });
+
+    Ok(summary)
+}
+
+/// Decrypts and authenticates every encrypted file under `paths` without
+/// writing anything.
+fn run_verify(paths: &[PathBuf], show_password: bool, jobs: usize) -> Result<Summary, String> {
+    check_paths_exist(paths)?;
+    let files = expand_encrypted_inputs(paths)?;
+    let file_paths: Vec<&Path> = files.iter().map(PathBuf::as_path).collect();
+    let session = prompt_passwords(&file_paths, show_password)?;
+
+    let item_jobs = jobs_per_item(jobs, files.len());
+    let mut summary = Summary::default();
+    run_with_passwords(&files, session, jobs, |file, password| {
+        authenticate_opened_file(file, password, item_jobs)
+    }, |path, result| match result {
+        Ok(()) => {
+            println!("✓ OK {}", path.display());
+            summary.verified += 1;
+        }
+        Err(e) => {
+            eprintln!("❌ FAIL {}: {}", path.display(), e);
+            summary.failed += 1;
+        }
+    });
+
+    Ok(summary)
+}
+
+fn format_listed(entry: &ListedEntry) -> String {
+    let path = entry.path.display();
+    let (kind, name) = match &entry.kind {
+        ListedKind::Directory => ('d', format!("{}/", path)),
+        ListedKind::File => ('-', path.to_string()),
+        ListedKind::Symlink(target) => ('l', format!("{} -> {}", path, target.display())),
+        ListedKind::HardLink(target) => ('h', format!("{} => {}", path, target.display())),
+        ListedKind::Fifo => ('p', path.to_string()),
+        ListedKind::Other => ('?', path.to_string()),
+    };
+    format!("  {} {:>12}  {}", kind, entry.size, name)
+}
 
-        let mut success_count = 0;
-        let mut error_count = 0;
+/// Prints what decrypting each encrypted file under `paths` would write,
+/// without writing anything.
+fn run_ls(paths: &[PathBuf], show_password: bool, jobs: usize) -> Result<Summary, String> {
+    check_paths_exist(paths)?;
+    let files = expand_encrypted_inputs(paths)?;
+    let file_paths: Vec<&Path> = files.iter().map(PathBuf::as_path).collect();
+    let session = prompt_passwords(&file_paths, show_password)?;
 
-        for file_path_str in files {
-            let file_path = Path::new(&file_path_str);
-            if !file_path.exists() {
-                eprintln!("❌ Error: Path does not exist: {}", file_path.display());
-                error_count += 1;
-                continue;
+    let item_jobs = jobs_per_item(jobs, files.len());
+    let mut summary = Summary::default();
+    run_with_passwords(&files, session, jobs, |file, password| {
+        list_opened_file(file, password, item_jobs)
+    }, |path, result| match result {
+        Ok(entries) => {
+            println!("{}:", path.display());
+            for entry in &entries {
+                println!("{}", format_listed(entry));
             }
+            summary.listed += 1;
+        }
+        Err(e) => {
+            eprintln!("❌ Error processing {}: {}", path.display(), e);
+            summary.failed += 1;
+        }
+    });
+
+    Ok(summary)
+}
 
-            match process_path(file_path, cli.show_password) {
-                Ok(_) => success_count += 1,
-                Err(e) => {
-                    eprintln!("❌ Error processing {}: {}", file_path.display(), e);
-                    error_count += 1;
-                }
+/// Prints the header of each file in `paths`, as text or as one JSON array;
+/// no password is needed. Files that can't be read are reported on stderr
+/// and left out. Returns how many there were.
+fn run_info(paths: &[PathBuf], json: bool) -> Result<usize, String> {
+    let mut failed = 0;
+    let mut infos = Vec::new();
+    for path in paths {
+        match FileInfo::read(path) {
+            Ok(info) => infos.push(info),
+            Err(e) => {
+                eprintln!("❌ Error reading {}: {}", path.display(), e);
+                failed += 1;
             }
         }
+    }
+
+    if json {
+        let output = serde_json::to_string_pretty(&infos)
+            .map_err(|e| format!("Failed to format JSON: {}", e))?;
+        println!("{}", output);
+        return Ok(failed);
+    }
+
+    for (index, info) in infos.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        println!("{}:", info.path.display());
+        for (label, value) in info.fields() {
+            println!("  {:<17}{}", format!("{}:", label), value);
+        }
+    }
+    Ok(failed)
+}
+
+/// Prints the summary of a batch of several items and exits with an error
+/// if any of them failed.
+fn finish_batch(summary: &Summary) {
+    if summary.total() > 1 {
+        summary.print();
+    }
+    if summary.failed > 0 {
+        std::process::exit(1);
+    }
+}
 
-        if success_count > 0 {
-            println!("\n✓ Successfully processed {} file(s)", success_count);
+/// Reads the paths of a batch from the command line, `--files-from` and,
+/// without any, an interactive selection. `None` means there is nothing to
+/// do, and why has been printed.
+fn collect_paths(batch: &BatchArgs) -> Result<Option<Vec<PathBuf>>, String> {
+    let mut paths = batch.paths.clone();
+    if let Some(source) = &batch.files_from {
+        paths.extend(read_path_list(source)?);
+        if source == Path::new("-") {
+            mark_stdin_consumed();
         }
-        if error_count > 0 {
-            eprintln!("❌ Failed to process {} file(s)", error_count);
-            std::process::exit(1);
+        if paths.is_empty() {
+            println!("No files to process");
+            return Ok(None);
         }
-        Ok(())
+    }
+
+    if paths.is_empty() {
+        let files = select_files_interactive()?;
+        if files.is_empty() {
+            println!("No files selected");
+            return Ok(None);
+        }
+        paths = files.iter().map(PathBuf::from).collect();
+    }
+
+    Ok(Some(paths))
+}
+
+/// Settings of one `encrypt`, `decrypt` or plain run. Flags that the
+/// subcommand doesn't take keep their defaults.
+struct BatchRun<'a> {
+    batch: &'a BatchArgs,
+    encrypting: &'a EncryptArgs,
+    decrypting: &'a DecryptArgs,
+    extended: &'a ExtendedArgs,
+    mode: Mode,
+}
+
+fn run_paths(run: BatchRun, config: &Config, show_password: bool, jobs: usize) -> Result<(), String> {
+    let BatchRun { batch, encrypting, decrypting, extended, mode } = run;
+
+    let output = OutputOptions {
+        path: batch.output.clone(),
+        dir: batch.out_dir.clone(),
+        on_conflict: batch.on_conflict.or(config.on_conflict).unwrap_or_default(),
+        create_dir: false,
+    };
+    let attributes = ExtendedAttributes {
+        xattrs: !extended.no_xattrs,
+        acls: !extended.no_acls,
+        ownership: !decrypting.no_owner,
+    };
+    let encrypt_options = EncryptOptions {
+        verify: !encrypting.no_verify,
+        keep: encrypting.keep || (config.keep && !encrypting.no_keep),
+        output: output.clone(),
+        archive: ArchiveOptions {
+            follow_symlinks: encrypting.follow_symlinks,
+            special_files: encrypting.special_files,
+            extended: attributes,
+            ignore_unreadable: encrypting.ignore_unreadable,
+            filter: FolderFilter {
+                exclude: encrypting.exclude.clone(),
+                include: encrypting.include.clone(),
+                respect_gitignore: encrypting.respect_gitignore,
+                one_file_system: encrypting.one_file_system,
+                min_size: encrypting.min_size,
+                max_size: encrypting.max_size,
+                modified_after: encrypting.modified_after,
+                modified_before: encrypting.modified_before,
+            },
+        },
+        master_salt: None,
+        jobs,
+    };
+    let decrypt_options = DecryptOptions {
+        keep_encrypted: decrypting.keep_encrypted || (config.keep_encrypted && !decrypting.no_keep_encrypted),
+        preserve: !decrypting.no_preserve,
+        output,
+        restore: attributes,
+        jobs,
     };
 
+    let Some(paths) = collect_paths(batch)? else {
+        return Ok(());
+    };
+    run_batch(&paths, show_password, batch.per_file, mode, &encrypt_options, &decrypt_options)
+        .map(|summary| finish_batch(&summary))
+}
+
+fn rewrite_options(rewrite: &RewriteArgs, jobs: usize) -> UpgradeOptions {
+    UpgradeOptions {
+        verify: !rewrite.no_verify,
+        kdf_iterations: rewrite.kdf_iterations,
+        master_salt: None,
+        jobs,
+    }
+}
+
+fn run(cli: Cli, config: &Config) -> Result<(), String> {
+    let jobs = cli.jobs.map(NonZeroUsize::get).unwrap_or_else(default_jobs);
+    let show_password = cli.show_password;
+
+    match &cli.command {
+        None => {
+            let mode = if cli.encrypt {
+                Mode::Encrypt
+            } else if cli.decrypt {
+                Mode::Decrypt
+            } else {
+                Mode::Auto
+            };
+            let run = BatchRun {
+                batch: &cli.batch,
+                encrypting: &cli.encrypting,
+                decrypting: &cli.decrypting,
+                extended: &cli.extended,
+                mode,
+            };
+            run_paths(run, config, show_password, jobs)
+        }
+        Some(Command::Encrypt { batch, encrypting, extended }) => {
+            let run = BatchRun { batch, encrypting, decrypting: &DecryptArgs::default(), extended, mode: Mode::Encrypt };
+            run_paths(run, config, show_password, jobs)
+        }
+        Some(Command::Decrypt { batch, decrypting, extended }) => {
+            let run = BatchRun { batch, encrypting: &EncryptArgs::default(), decrypting, extended, mode: Mode::Decrypt };
+            run_paths(run, config, show_password, jobs)
+        }
+        Some(Command::Info { paths, json }) => {
+            if run_info(paths, *json)? > 0 {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Some(Command::Verify { paths }) => run_verify(paths, show_password, jobs).map(|summary| finish_batch(&summary)),
+        Some(Command::Ls { paths }) => run_ls(paths, show_password, jobs).map(|summary| finish_batch(&summary)),
+        Some(Command::Rekey { paths, rewrite }) => {
+            run_rewrite(paths, show_password, true, &rewrite_options(rewrite, jobs)).map(|summary| finish_batch(&summary))
+        }
+        Some(Command::Upgrade { paths, rewrite }) => {
+            run_rewrite(paths, show_password, false, &rewrite_options(rewrite, jobs)).map(|summary| finish_batch(&summary))
+        }
+        Some(Command::Keygen { length }) => {
+            println!("{}", generate_password(usize::from(*length)));
+            Ok(())
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse_args();
+    let result = Config::load().and_then(|config| run(cli, &config));
+
     if let Err(e) = result {
         eprintln!("❌ Error: {}", e);
         std::process::exit(1);
     }
 }
-
@@ -1,15 +1,97 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+
+use crate::attributes::FileAttributes;
+use crate::encryption::{DEFAULT_PBKDF2_ITERATIONS, MAX_PBKDF2_ITERATIONS};
+use crate::tlv::{self, TlvWriter, CRITICAL};
 
 pub const MAGIC_BYTES: &[u8; 4] = b"ECTF";
-pub const VERSION: u8 = 0x01;
+/// Version 1: the encrypted payload is the raw file or tar data.
+/// Version 2: the metadata is a sequence of [`crate::tlv`] records, so new
+/// fields no longer need a new version, and records how the key is derived
+/// ([`KeyDerivation`]). The payload is encrypted in segments
+/// ([`crate::segments`]), and file payloads start with length-prefixed
+/// [`FileAttributes`].
+pub const VERSION: u8 = 0x02;
+pub const MIN_VERSION: u8 = 0x01;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Largest serialized metadata a header may declare.
+pub const MAX_METADATA_LEN: usize = 64 * 1024;
+/// Largest helper question, in bytes.
+pub const MAX_HELPER_QUESTION_LEN: usize = 4096;
+/// Largest original file or folder name, in bytes.
+pub const MAX_ORIGINAL_NAME_LEN: usize = 1024;
+/// Largest serialized [`FileAttributes`] at the start of a file payload.
+pub const MAX_ATTRIBUTES_LEN: usize = 4096;
+
+/// The fixed-size start of every file: magic bytes, version and metadata length.
+pub const HEADER_PREFIX_LEN: usize = 9;
+
+/// The bincode settings `bincode::serialize` uses, plus a cap on how many
+/// bytes one value may take. Lengths inside the data are checked against the
+/// cap before anything is allocated, so a forged string length fails instead
+/// of reserving gigabytes.
+fn bincode_options(limit: usize) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit as u64)
+}
+
+/// Record tags of the version 2 metadata. Fields a reader can't do without
+/// are [`CRITICAL`]; a reader skips optional fields it doesn't know, and
+/// refuses a file with a critical field it doesn't know.
+mod field {
+    use super::CRITICAL;
+
+    pub const NONCE: u16 = CRITICAL | 0x01;
+    pub const SALT: u16 = CRITICAL | 0x02;
+    /// One byte: 0 for a file, 1 for a folder.
+    pub const CONTENT_TYPE: u16 = CRITICAL | 0x03;
+    pub const ORIGINAL_NAME: u16 = CRITICAL | 0x04;
+    /// One byte: 0 for [`super::KeyDerivation::Pbkdf2`], 1 for
+    /// [`super::KeyDerivation::Pbkdf2Hkdf`] followed by the file salt.
+    pub const KEY_DERIVATION: u16 = CRITICAL | 0x05;
+    pub const HELPER_QUESTION: u16 = 0x06;
+    /// Little-endian `u32` PBKDF2 rounds. Only written when they differ from
+    /// [`super::DEFAULT_PBKDF2_ITERATIONS`], which a header without it uses.
+    pub const KDF_ITERATIONS: u16 = CRITICAL | 0x07;
+}
+
+#[derive(Debug, Clone)]
 pub struct Metadata {
     pub nonce: [u8; 12],
     pub salt: [u8; 32],
     pub helper_question: String,
     pub original_name: String,
     pub content_type: ContentType,
+    pub key_derivation: KeyDerivation,
+    /// PBKDF2 rounds for the password.
+    pub kdf_iterations: u32,
+    /// Format version the header was read from; not serialized.
+    pub version: u8,
+}
+
+/// Header layout of version 1, serialized with bincode.
+#[derive(Deserialize)]
+struct LegacyMetadata {
+    nonce: [u8; 12],
+    salt: [u8; 32],
+    helper_question: String,
+    original_name: String,
+    content_type: ContentType,
+}
+
+/// How the file key is derived from the password.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyDerivation {
+    /// PBKDF2 over the password and `salt` gives the file key.
+    Pbkdf2,
+    /// PBKDF2 over the password and `salt` gives a master key shared by all
+    /// files of one operation; HKDF with `file_salt` turns it into this
+    /// file's key.
+    Pbkdf2Hkdf { file_salt: [u8; 32] },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,6 +100,21 @@ pub enum ContentType {
     Folder,
 }
 
+/// `original_name` comes from the file header and is joined onto the output
+/// directory, so it must be a single plain file name.
+pub fn validate_original_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(format!("Invalid original name {:?}", name));
+    }
+    if name.contains(['/', '\\', '\0']) {
+        return Err(format!("Original name {:?} contains a path separator or NUL", name));
+    }
+    if name.len() >= 2 && name.as_bytes()[1] == b':' && name.as_bytes()[0].is_ascii_alphabetic() {
+        return Err(format!("Original name {:?} looks like a drive path", name));
+    }
+    Ok(())
+}
+
 impl Metadata {
     pub fn new(nonce: [u8; 12], salt: [u8; 32], helper_question: String, original_name: String, content_type: ContentType) -> Self {
         Self {
@@ -26,16 +123,236 @@ impl Metadata {
             helper_question,
             original_name,
             content_type,
+            key_derivation: KeyDerivation::Pbkdf2,
+            kdf_iterations: DEFAULT_PBKDF2_ITERATIONS,
+            version: VERSION,
+        }
+    }
+
+    pub fn with_key_derivation(self, key_derivation: KeyDerivation) -> Self {
+        Self { key_derivation, ..self }
+    }
+
+    pub fn with_kdf_iterations(self, kdf_iterations: u32) -> Self {
+        Self { kdf_iterations, ..self }
+    }
+
+    /// Serializes the metadata as [`VERSION`] records.
+    pub fn serialize(&self) -> Result<Vec<u8>, String> {
+        let key_derivation = match self.key_derivation {
+            KeyDerivation::Pbkdf2 => vec![0],
+            KeyDerivation::Pbkdf2Hkdf { file_salt } => [&[1][..], &file_salt].concat(),
+        };
+        let content_type = match self.content_type {
+            ContentType::File => 0,
+            ContentType::Folder => 1,
+        };
+
+        let mut writer = TlvWriter::new();
+        writer
+            .field(field::NONCE, &self.nonce)?
+            .field(field::SALT, &self.salt)?
+            .field(field::CONTENT_TYPE, &[content_type])?
+            .field(field::ORIGINAL_NAME, self.original_name.as_bytes())?
+            .field(field::KEY_DERIVATION, &key_derivation)?
+            .field(field::HELPER_QUESTION, self.helper_question.as_bytes())?;
+        if self.kdf_iterations != DEFAULT_PBKDF2_ITERATIONS {
+            writer.field(field::KDF_ITERATIONS, &self.kdf_iterations.to_le_bytes())?;
+        }
+        Ok(writer.finish())
+    }
+
+    /// Reads the records of a version 2 header.
+    fn from_records(data: &[u8]) -> Result<Self, String> {
+        let mut nonce = None;
+        let mut salt = None;
+        let mut content_type = None;
+        let mut original_name = None;
+        let mut key_derivation = None;
+        let mut helper_question = None;
+        let mut kdf_iterations = None;
+
+        for record in tlv::parse_records(data)? {
+            let value = record.value;
+            match record.tag {
+                field::NONCE => set_once(&mut nonce, "nonce", fixed(value, "nonce")?)?,
+                field::SALT => set_once(&mut salt, "salt", fixed(value, "salt")?)?,
+                field::CONTENT_TYPE => {
+                    let parsed = match value {
+                        [0] => ContentType::File,
+                        [1] => ContentType::Folder,
+                        _ => return Err("Unsupported content type in header".to_string()),
+                    };
+                    set_once(&mut content_type, "content type", parsed)?;
+                }
+                field::ORIGINAL_NAME => set_once(&mut original_name, "original name", text(value, "original name")?)?,
+                field::KEY_DERIVATION => {
+                    let parsed = match value {
+                        [0] => KeyDerivation::Pbkdf2,
+                        [1, file_salt @ ..] => KeyDerivation::Pbkdf2Hkdf { file_salt: fixed(file_salt, "file salt")? },
+                        _ => return Err("Unsupported key derivation in header".to_string()),
+                    };
+                    set_once(&mut key_derivation, "key derivation", parsed)?;
+                }
+                field::HELPER_QUESTION => set_once(&mut helper_question, "helper question", text(value, "helper question")?)?,
+                field::KDF_ITERATIONS => {
+                    let iterations = u32::from_le_bytes(fixed(value, "iteration count")?);
+                    if !(1..=MAX_PBKDF2_ITERATIONS).contains(&iterations) {
+                        return Err(format!("Unsupported iteration count {} in header (at most {})", iterations, MAX_PBKDF2_ITERATIONS));
+                    }
+                    set_once(&mut kdf_iterations, "iteration count", iterations)?;
+                }
+                tag if tlv::is_critical(tag) => {
+                    return Err(format!("Unsupported header field {:#06x}; a newer version of ectfy may be needed", tag));
+                }
+                _ => {}
+            }
+        }
+
+        let missing = |name: &str| format!("Header has no {}", name);
+        Ok(Self {
+            nonce: nonce.ok_or_else(|| missing("nonce"))?,
+            salt: salt.ok_or_else(|| missing("salt"))?,
+            helper_question: helper_question.unwrap_or_default(),
+            original_name: original_name.ok_or_else(|| missing("original name"))?,
+            content_type: content_type.ok_or_else(|| missing("content type"))?,
+            key_derivation: key_derivation.ok_or_else(|| missing("key derivation"))?,
+            kdf_iterations: kdf_iterations.unwrap_or(DEFAULT_PBKDF2_ITERATIONS),
+            version: VERSION,
+        })
+    }
+
+    /// Reads metadata written in format `version`. At most
+    /// [`MAX_METADATA_LEN`] bytes are read, and every string must be within
+    /// its own maximum. Never panics, whatever `data` holds.
+    pub fn deserialize(version: u8, data: &[u8]) -> Result<Self, String> {
+        let options = bincode_options(MAX_METADATA_LEN);
+        let mut metadata = if version < 2 {
+            let legacy: LegacyMetadata = options.deserialize(data)
+                .map_err(|e| format!("Failed to deserialize metadata: {}", e))?;
+            Self::new(legacy.nonce, legacy.salt, legacy.helper_question, legacy.original_name, legacy.content_type)
+        } else if data.len() > MAX_METADATA_LEN {
+            return Err(format!("Metadata is too large ({} bytes, at most {})", data.len(), MAX_METADATA_LEN));
+        } else {
+            Self::from_records(data)?
+        };
+        metadata.version = version;
+
+        if metadata.helper_question.len() > MAX_HELPER_QUESTION_LEN {
+            return Err(format!("Helper question is too long ({} bytes, at most {})", metadata.helper_question.len(), MAX_HELPER_QUESTION_LEN));
+        }
+        if metadata.original_name.len() > MAX_ORIGINAL_NAME_LEN {
+            return Err(format!("Original name is too long ({} bytes, at most {})", metadata.original_name.len(), MAX_ORIGINAL_NAME_LEN));
         }
+        validate_original_name(&metadata.original_name)?;
+
+        Ok(metadata)
+    }
+}
+
+fn set_once<T>(slot: &mut Option<T>, name: &str, value: T) -> Result<(), String> {
+    if slot.replace(value).is_some() {
+        return Err(format!("Header has more than one {}", name));
+    }
+    Ok(())
+}
+
+fn fixed<const N: usize>(value: &[u8], name: &str) -> Result<[u8; N], String> {
+    value.try_into().map_err(|_| format!("Header {} has {} bytes instead of {}", name, value.len(), N))
+}
+
+fn text(value: &[u8], name: &str) -> Result<String, String> {
+    String::from_utf8(value.to_vec()).map_err(|_| format!("Header {} is not valid UTF-8", name))
+}
+
+/// Parses the header at the start of `reader` and leaves `reader` at the
+/// start of the payload. Returns the metadata and the header length.
+///
+/// The header is the four [`MAGIC_BYTES`], a version byte between
+/// [`MIN_VERSION`] and [`VERSION`], a little-endian `u32` metadata length of
+/// at most [`MAX_METADATA_LEN`], then that many bytes of [`Metadata`]: TLV
+/// records since version 2, bincode before (see [`Metadata::deserialize`]
+/// for the field limits). The
+/// parser reads no more than the declared length, allocates no more than
+/// [`MAX_METADATA_LEN`], and reports every malformed input as an error
+/// rather than panicking.
+pub fn read_header_from<R: Read>(reader: &mut R) -> Result<(Metadata, u64), String> {
+    let mut prefix = [0u8; HEADER_PREFIX_LEN];
+    reader.read_exact(&mut prefix).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => "File appears to be corrupted (too short)".to_string(),
+        _ => format!("Failed to read file: {}", e),
+    })?;
+
+    let magic = &prefix[0..4];
+    if magic != MAGIC_BYTES {
+        return Err("File appears to be corrupted (invalid magic bytes)".to_string());
     }
 
-    pub fn serialize(&self) -> Result<Vec<u8>, bincode::Error> {
-        bincode::serialize(self)
+    let version = prefix[4];
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(format!("Unsupported file version: {}", version));
     }
 
-    pub fn deserialize(data: &[u8]) -> Result<Self, bincode::Error> {
-        bincode::deserialize(data)
+    let metadata_len = u32::from_le_bytes([
+        prefix[5], prefix[6], prefix[7], prefix[8]
+    ]) as usize;
+    if metadata_len > MAX_METADATA_LEN {
+        return Err(format!("File appears to be corrupted (metadata length {} exceeds {})", metadata_len, MAX_METADATA_LEN));
     }
+
+    let mut metadata_bytes = Vec::with_capacity(metadata_len);
+    reader.take(metadata_len as u64).read_to_end(&mut metadata_bytes)
+        .map_err(|e| format!("Failed to read file: {}", e))?;
+    if metadata_bytes.len() < metadata_len {
+        return Err("File appears to be corrupted (metadata length invalid)".to_string());
+    }
+
+    let metadata = Metadata::deserialize(version, &metadata_bytes)?;
+    Ok((metadata, (HEADER_PREFIX_LEN + metadata_len) as u64))
+}
+
+/// Builds the plaintext for a `ContentType::File` payload: a little-endian
+/// `u32` length, the serialized attributes, then the file contents.
+pub fn encode_file_payload(attributes: &FileAttributes, data: &[u8]) -> Result<Vec<u8>, String> {
+    let attributes_bytes = bincode::serialize(attributes)
+        .map_err(|e| format!("Failed to serialize file attributes: {}", e))?;
+
+    let mut payload = Vec::with_capacity(4 + attributes_bytes.len() + data.len());
+    payload.extend_from_slice(&(attributes_bytes.len() as u32).to_le_bytes());
+    payload.extend_from_slice(&attributes_bytes);
+    payload.extend_from_slice(data);
+    Ok(payload)
+}
+
+/// Splits a decrypted `ContentType::File` payload into its attributes and
+/// contents. Version 1 payloads carry no attributes.
+pub fn decode_file_payload(version: u8, payload: &[u8]) -> Result<(FileAttributes, &[u8]), String> {
+    if version < 2 {
+        return Ok((FileAttributes::default(), payload));
+    }
+
+    let Some(&[a, b, c, d]) = payload.get(..4) else {
+        return Err("File payload is truncated".to_string());
+    };
+    let attributes_len = u32::from_le_bytes([a, b, c, d]) as usize;
+    if attributes_len > MAX_ATTRIBUTES_LEN {
+        return Err(format!("File attributes are too large ({} bytes, at most {})", attributes_len, MAX_ATTRIBUTES_LEN));
+    }
+    let attributes_bytes = payload.get(4..4 + attributes_len)
+        .ok_or_else(|| "File payload is truncated".to_string())?;
+
+    let attributes = bincode_options(MAX_ATTRIBUTES_LEN).deserialize(attributes_bytes)
+        .map_err(|e| format!("Failed to deserialize file attributes: {}", e))?;
+
+    Ok((attributes, &payload[4 + attributes_len..]))
+}
+
+/// A file written in format `version` for the tests: `hello.txt.ect` or the
+/// folder `notes.ect`, with the password `golden`. See
+/// `testdata/golden/README.md` for how they were made.
+#[cfg(test)]
+pub(crate) fn golden(version: u8, name: &str) -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/golden").join(format!("v{}", version)).join(name)
 }
 
 #[cfg(test)]
@@ -55,7 +372,7 @@ mod tests {
         );
 
         let serialized = metadata.serialize().unwrap();
-        let deserialized = Metadata::deserialize(&serialized).unwrap();
+        let deserialized = Metadata::deserialize(VERSION, &serialized).unwrap();
 
         assert_eq!(metadata.helper_question, deserialized.helper_question);
         assert_eq!(metadata.original_name, deserialized.original_name);
@@ -63,5 +380,115 @@ mod tests {
         assert_eq!(metadata.salt, deserialized.salt);
         assert_eq!(format!("{:?}", metadata.content_type), format!("{:?}", deserialized.content_type));
     }
+
+    #[test]
+    fn test_file_payload_round_trip() {
+        let attributes = FileAttributes { mode: Some(0o755), uid: Some(1000), ..FileAttributes::default() };
+        let payload = encode_file_payload(&attributes, b"#!/bin/sh\n").unwrap();
+
+        let (decoded, data) = decode_file_payload(VERSION, &payload).unwrap();
+        assert_eq!(decoded, attributes);
+        assert_eq!(data, b"#!/bin/sh\n");
+
+        let (decoded, data) = decode_file_payload(1, b"raw").unwrap();
+        assert_eq!(decoded, FileAttributes::default());
+        assert_eq!(data, b"raw");
+
+        assert!(decode_file_payload(VERSION, &payload[..6]).is_err());
+    }
+
+    fn header(version: u8, metadata_bytes: &[u8]) -> Vec<u8> {
+        let mut header = MAGIC_BYTES.to_vec();
+        header.push(version);
+        header.extend_from_slice(&(metadata_bytes.len() as u32).to_le_bytes());
+        header.extend_from_slice(metadata_bytes);
+        header
+    }
+
+    #[test]
+    fn test_read_header_limits() {
+        let metadata = Metadata::new([1u8; 12], [2u8; 32], "Pet?".to_string(), "a.txt".to_string(), ContentType::File);
+        let valid = header(VERSION, &metadata.serialize().unwrap());
+
+        let (parsed, length) = read_header_from(&mut valid.as_slice()).unwrap();
+        assert_eq!(parsed.helper_question, "Pet?");
+        assert_eq!(length, valid.len() as u64);
+
+        for end in 0..valid.len() {
+            assert!(read_header_from(&mut &valid[..end]).is_err(), "prefix of {} bytes", end);
+        }
+
+        let mut oversized = valid.clone();
+        oversized[5..9].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_header_from(&mut oversized.as_slice()).unwrap_err().contains("exceeds"));
+
+        // A forged string length in a bincode header must fail before
+        // anything is allocated: the nonce and salt take 44 bytes, then
+        // comes the helper question's length.
+        let mut forged = vec![0u8; 44];
+        forged.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        let error = read_header_from(&mut header(1, &forged).as_slice()).unwrap_err();
+        assert!(error.starts_with("Failed to deserialize metadata"), "{}", error);
+
+        let long_question = Metadata::new([0u8; 12], [0u8; 32], "?".repeat(MAX_HELPER_QUESTION_LEN + 1), "a.txt".to_string(), ContentType::File);
+        let error = read_header_from(&mut header(VERSION, &long_question.serialize().unwrap()).as_slice()).unwrap_err();
+        assert!(error.contains("too long"), "{}", error);
+
+        let mut huge_attributes = (u32::MAX).to_le_bytes().to_vec();
+        huge_attributes.extend_from_slice(b"data");
+        assert!(decode_file_payload(VERSION, &huge_attributes).is_err());
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let file_salt = [9u8; 32];
+        let metadata = Metadata::new([1u8; 12], [2u8; 32], "Pet?".to_string(), "photos".to_string(), ContentType::Folder)
+            .with_key_derivation(KeyDerivation::Pbkdf2Hkdf { file_salt });
+
+        let with_field = |tag: u16, value: &[u8]| {
+            let mut data = metadata.serialize().unwrap();
+            let mut writer = TlvWriter::new();
+            writer.field(tag, value).unwrap();
+            data.extend_from_slice(&writer.finish());
+            Metadata::deserialize(VERSION, &data)
+        };
+
+        let parsed = with_field(0x7001, b"an optional field from a newer version").unwrap();
+        assert_eq!(parsed.original_name, "photos");
+        assert_eq!(parsed.helper_question, "Pet?");
+        assert_eq!(parsed.key_derivation, metadata.key_derivation);
+        assert!(matches!(parsed.content_type, ContentType::Folder));
+
+        let error = with_field(CRITICAL | 0x7001, b"").unwrap_err();
+        assert!(error.contains("Unsupported header field 0xf001"), "{}", error);
+        let error = with_field(field::ORIGINAL_NAME, b"other").unwrap_err();
+        assert!(error.contains("more than one original name"), "{}", error);
+
+        let mut writer = TlvWriter::new();
+        writer.field(field::NONCE, &[0u8; 12]).unwrap().field(field::SALT, &[0u8; 31]).unwrap();
+        let error = Metadata::deserialize(VERSION, &writer.finish()).unwrap_err();
+        assert!(error.contains("salt has 31 bytes"), "{}", error);
+
+        let stronger = metadata.clone().with_kdf_iterations(500_000);
+        assert_eq!(Metadata::deserialize(VERSION, &stronger.serialize().unwrap()).unwrap().kdf_iterations, 500_000);
+        assert_eq!(parsed.kdf_iterations, DEFAULT_PBKDF2_ITERATIONS);
+        let error = with_field(field::KDF_ITERATIONS, &u32::MAX.to_le_bytes()).unwrap_err();
+        assert!(error.contains("Unsupported iteration count"), "{}", error);
+
+        let mut writer = TlvWriter::new();
+        writer.field(field::NONCE, &[0u8; 12]).unwrap();
+        let error = Metadata::deserialize(VERSION, &writer.finish()).unwrap_err();
+        assert!(error.contains("Header has no salt"), "{}", error);
+    }
+
+    #[test]
+    fn test_validate_original_name() {
+        for name in ["test.txt", "test file with spaces.txt", ".hidden", "..dots", "photo.jpg"] {
+            assert!(validate_original_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", ".", "..", "../../.bashrc", "/etc/passwd", "a/b", "a\\b", "nul\0byte", "C:evil"] {
+            assert!(validate_original_name(name).is_err(), "{}", name);
+        }
+    }
 }
 
@@ -1,14 +1,38 @@
 use rpassword::read_password;
-use std::io::{self, Write};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use zeroize::Zeroize;
 
+/// Set once stdin has been used for something else, such as a list of files.
+static STDIN_CONSUMED: AtomicBool = AtomicBool::new(false);
+
+/// Makes prompts read from the terminal instead of stdin from now on.
+pub fn mark_stdin_consumed() {
+    STDIN_CONSUMED.store(true, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+const TERMINAL: &str = "/dev/tty";
+#[cfg(windows)]
+const TERMINAL: &str = "CONIN$";
+
+/// Reads one line of an answer to a prompt, like `Stdin::read_line`.
+pub fn read_line(buffer: &mut String) -> io::Result<usize> {
+    if STDIN_CONSUMED.load(Ordering::Relaxed) {
+        BufReader::new(File::open(TERMINAL)?).read_line(buffer)
+    } else {
+        io::stdin().read_line(buffer)
+    }
+}
+
 pub fn prompt_password(prompt: &str, show_password: bool) -> io::Result<String> {
     print!("{}", prompt);
     io::stdout().flush()?;
     
     if show_password {
         let mut password = String::new();
-        io::stdin().read_line(&mut password)?;
+        read_line(&mut password)?;
         Ok(password.trim().to_string())
     } else {
         read_password()
@@ -41,8 +65,31 @@ pub fn get_password(show_password: bool) -> io::Result<String> {
     prompt_password("Enter password: ", show_password)
 }
 
+/// Characters of generated passwords: letters and digits, leaving out the
+/// ones that are easy to mix up (`0`/`O`, `1`/`l`/`I`).
+const GENERATED_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz23456789";
+
+/// A random password of `length` characters for `keygen`.
+pub fn generate_password(length: usize) -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    (0..length)
+        .map(|_| GENERATED_ALPHABET[rng.gen_range(0..GENERATED_ALPHABET.len())] as char)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_password() {
+        let password = generate_password(32);
+        assert_eq!(password.len(), 32);
+        assert!(password.bytes().all(|c| GENERATED_ALPHABET.contains(&c)));
+        assert_ne!(password, generate_password(32));
+    }
+
     #[test]
     fn test_password_confirmation_mismatch() {
         // This test would require mocking stdin, which is complex
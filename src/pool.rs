@@ -0,0 +1,86 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// The pool size used when `--jobs` is not given: one worker per CPU.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(1)
+}
+
+/// How many threads each of `items` items running at once may use for its
+/// own work, so nested parallelism stays within `jobs` threads overall.
+pub fn jobs_per_item(jobs: usize, items: usize) -> usize {
+    (jobs / items.clamp(1, jobs.max(1))).max(1)
+}
+
+/// Runs `work` on every item with up to `jobs` threads. `handle` gets each
+/// item with its result on the calling thread, in the order of `items`, as
+/// soon as the result and all earlier ones are ready, so output stays the
+/// same however the work was scheduled.
+pub fn run_parallel<T, R, W, H>(items: &[T], jobs: usize, work: W, mut handle: H)
+where
+    T: Sync,
+    R: Send,
+    W: Fn(&T) -> R + Sync,
+    H: FnMut(&T, R),
+{
+    let workers = jobs.clamp(1, items.len().max(1));
+    if workers == 1 {
+        for item in items {
+            handle(item, work(item));
+        }
+        return;
+    }
+
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let (next, work) = (&next, &work);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+                if sender.send((index, work(item))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending: Vec<Option<R>> = items.iter().map(|_| None).collect();
+        let mut handled = 0;
+        for (index, result) in receiver {
+            pending[index] = Some(result);
+            while let Some(result) = pending.get_mut(handled).and_then(Option::take) {
+                handle(&items[handled], result);
+                handled += 1;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_results_keep_input_order() {
+        let items: Vec<u64> = (0..200).collect();
+        let mut results = Vec::new();
+        run_parallel(&items, 8, |item| {
+            thread::sleep(std::time::Duration::from_micros((200 - item) * 10));
+            item * 2
+        }, |item, result| results.push((*item, result)));
+
+        assert_eq!(results, items.iter().map(|item| (*item, item * 2)).collect::<Vec<_>>());
+
+        let mut called = false;
+        run_parallel(&[] as &[u64], 4, |item| *item, |_, _| called = true);
+        assert!(!called);
+    }
+}
@@ -0,0 +1,300 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Key, Nonce,
+};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use crate::file_ops::DecryptError;
+
+/// Plaintext bytes per segment. Every segment but the last has exactly this
+/// many; the last has fewer, possibly none.
+pub const SEGMENT_SIZE: usize = 1 << 20;
+const TAG_SIZE: usize = 16;
+/// Segments in flight for each worker, counting those waiting to be written.
+const SEGMENTS_PER_JOB: usize = 2;
+
+/// The nonce of one segment, as in the STREAM construction: the first seven
+/// bytes of the file nonce, the big-endian segment index and a flag that is
+/// only set on the last segment. Reordered, dropped or appended segments
+/// therefore fail to authenticate, and the output does not depend on how
+/// many threads did the work.
+fn segment_nonce(nonce: &[u8; 12], index: u32, last: bool) -> [u8; 12] {
+    let mut segment_nonce = [0u8; 12];
+    segment_nonce[..7].copy_from_slice(&nonce[..7]);
+    segment_nonce[7..11].copy_from_slice(&index.to_be_bytes());
+    segment_nonce[11] = last as u8;
+    segment_nonce
+}
+
+struct Segment {
+    index: u32,
+    last: bool,
+    data: Vec<u8>,
+}
+
+/// Reads until `buffer` is full or the input ends.
+fn read_full(input: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// A transformed segment on its way back to be written in order.
+struct Processed<E> {
+    index: u32,
+    last: bool,
+    data: Result<Vec<u8>, E>,
+}
+
+/// Cuts `input` into segments of `chunk_size` bytes for the workers. A short
+/// read marks the last segment. Each segment waits for a credit, which the
+/// writer hands back once it has written one, so only so many segments are
+/// in flight however far the workers get ahead of the writer. A read error
+/// takes the place of the segment that could not be read.
+fn read_segments<R, E>(mut input: R, chunk_size: usize, credits: Receiver<()>, work: SyncSender<Segment>, results: SyncSender<Processed<E>>)
+where
+    R: Read,
+    E: From<String>,
+{
+    let mut index: u32 = 0;
+    while credits.recv().is_ok() {
+        let mut data = vec![0u8; chunk_size];
+        let read = match read_full(&mut input, &mut data) {
+            Ok(read) => read,
+            Err(e) => {
+                let _ = results.send(Processed { index, last: true, data: Err(E::from(format!("Failed to read input: {}", e))) });
+                return;
+            }
+        };
+        data.truncate(read);
+
+        let last = read < chunk_size;
+        if !last && index == u32::MAX {
+            let error = E::from("Failed to read input: input has too many segments".to_string());
+            let _ = results.send(Processed { index, last: true, data: Err(error) });
+            return;
+        }
+        if work.send(Segment { index, last, data }).is_err() || last {
+            return;
+        }
+        index += 1;
+    }
+}
+
+/// Transforms segments from `work` until the reader or the writer is gone.
+fn run_worker<F, E>(work: Arc<Mutex<Receiver<Segment>>>, results: SyncSender<Processed<E>>, transform: &F)
+where
+    F: Fn(&Segment) -> Result<Vec<u8>, E>,
+{
+    loop {
+        let Ok(segment) = work.lock().unwrap_or_else(PoisonError::into_inner).recv() else {
+            return;
+        };
+        let data = transform(&segment);
+        if results.send(Processed { index: segment.index, last: segment.last, data }).is_err() {
+            return;
+        }
+    }
+}
+
+/// Writes the results to `output` in segment order as they come in, handing
+/// back a credit for each one written. Returning drops both channels, which
+/// stops the reader and the workers.
+fn write_in_order<W, E>(results: Receiver<Processed<E>>, credits: SyncSender<()>, output: &mut W) -> Result<(), E>
+where
+    W: Write + ?Sized,
+    E: From<String>,
+{
+    let mut pending = BTreeMap::new();
+    let mut next: u32 = 0;
+    for processed in results {
+        pending.insert(processed.index, processed);
+        while let Some(processed) = pending.remove(&next) {
+            let data = processed.data?;
+            output.write_all(&data).map_err(|e| E::from(format!("Failed to write output: {}", e)))?;
+            if processed.last {
+                return Ok(());
+            }
+            let _ = credits.send(());
+            next += 1;
+        }
+    }
+
+    Err(E::from("Segment processing stopped before the last segment".to_string()))
+}
+
+/// Runs `transform` over the segments of `input` on `jobs` worker threads
+/// that live as long as the input, fed by a reader thread through a bounded
+/// channel, and writes the results to `output` in order.
+fn process_segments<R, W, F, E>(input: R, output: &mut W, chunk_size: usize, jobs: usize, transform: F) -> Result<(), E>
+where
+    R: Read + Send,
+    W: Write + ?Sized,
+    F: Fn(&Segment) -> Result<Vec<u8>, E> + Sync,
+    E: From<String> + Send,
+{
+    let jobs = jobs.max(1);
+    let in_flight = jobs * SEGMENTS_PER_JOB;
+    let (credit_sender, credits) = mpsc::sync_channel(in_flight);
+    for _ in 0..in_flight {
+        let _ = credit_sender.send(());
+    }
+    let (work_sender, work) = mpsc::sync_channel(in_flight);
+    let work = Arc::new(Mutex::new(work));
+    let (result_sender, results) = mpsc::sync_channel(in_flight);
+
+    thread::scope(|scope| {
+        let reader_results = result_sender.clone();
+        scope.spawn(move || read_segments(input, chunk_size, credits, work_sender, reader_results));
+        for _ in 0..jobs {
+            let (work, results, transform) = (work.clone(), result_sender.clone(), &transform);
+            scope.spawn(move || run_worker(work, results, transform));
+        }
+        drop((work, result_sender));
+
+        write_in_order(results, credit_sender, output)
+    })
+}
+
+/// Encrypts `input` segment by segment into `output`.
+pub fn encrypt_segments<R, W>(key: &Key<Aes256Gcm>, nonce: &[u8; 12], input: R, output: &mut W, jobs: usize) -> Result<(), String>
+where
+    R: Read + Send,
+    W: Write + ?Sized,
+{
+    let cipher = Aes256Gcm::new(key);
+    process_segments(input, output, SEGMENT_SIZE, jobs, |segment| {
+        let segment_nonce = segment_nonce(nonce, segment.index, segment.last);
+        cipher.encrypt(Nonce::from_slice(&segment_nonce), segment.data.as_slice())
+            .map_err(|e| format!("Encryption failed: {}", e))
+    })
+}
+
+/// Decrypts the segments in `input` into `output`, failing on the first
+/// segment that does not authenticate. Output written before that point must
+/// be thrown away. The key is expected to have been checked with
+/// [`decrypt_first_segment`], so a segment that fails is reported as damage.
+pub fn decrypt_segments<R, W>(key: &Key<Aes256Gcm>, nonce: &[u8; 12], input: R, output: &mut W, jobs: usize) -> Result<(), DecryptError>
+where
+    R: Read + Send,
+    W: Write + ?Sized,
+{
+    let cipher = Aes256Gcm::new(key);
+    process_segments(input, output, SEGMENT_SIZE + TAG_SIZE, jobs, |segment| {
+        let segment_nonce = segment_nonce(nonce, segment.index, segment.last);
+        cipher.decrypt(Nonce::from_slice(&segment_nonce), segment.data.as_slice())
+            .map_err(|_| DecryptError::Damaged(format!("segment {} failed authentication", segment.index as u64 + 1)))
+    })
+}
+
+/// Reads and decrypts only the first segment, so a wrong password is caught
+/// before any output is set up. Returns the ciphertext that was read, to be
+/// put back in front of the rest of `input`, and its plaintext.
+pub fn decrypt_first_segment<R: Read>(key: &Key<Aes256Gcm>, nonce: &[u8; 12], input: &mut R) -> Result<(Vec<u8>, Vec<u8>), DecryptError> {
+    let mut chunk = vec![0u8; SEGMENT_SIZE + TAG_SIZE];
+    let read = read_full(input, &mut chunk)
+        .map_err(|e| format!("Failed to read encrypted data: {}", e))?;
+    chunk.truncate(read);
+
+    let segment_nonce = segment_nonce(nonce, 0, read < SEGMENT_SIZE + TAG_SIZE);
+    let plaintext = Aes256Gcm::new(key).decrypt(Nonce::from_slice(&segment_nonce), chunk.as_slice())
+        .map_err(|_| DecryptError::WrongPassword)?;
+
+    Ok((chunk, plaintext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8], jobs: usize) -> Vec<u8> {
+        let key = Key::<Aes256Gcm>::from([7u8; 32]);
+        let nonce = [3u8; 12];
+
+        let mut ciphertext = Vec::new();
+        encrypt_segments(&key, &nonce, data, &mut ciphertext, jobs).unwrap();
+
+        let mut plaintext = Vec::new();
+        decrypt_segments(&key, &nonce, ciphertext.as_slice(), &mut plaintext, jobs).unwrap();
+        assert_eq!(plaintext, data);
+        ciphertext
+    }
+
+    #[test]
+    fn test_segments_round_trip_and_match_across_jobs() {
+        let data: Vec<u8> = (0..SEGMENT_SIZE * 3 + 17).map(|i| (i % 251) as u8).collect();
+
+        let single = round_trip(&data, 1);
+        assert_eq!(single.len(), data.len() + 4 * TAG_SIZE);
+        assert_eq!(round_trip(&data, 8), single);
+
+        // A whole number of segments ends with an empty one.
+        assert_eq!(round_trip(&data[..SEGMENT_SIZE], 4).len(), SEGMENT_SIZE + 2 * TAG_SIZE);
+        assert_eq!(round_trip(b"", 4).len(), TAG_SIZE);
+    }
+
+    #[test]
+    fn test_truncated_or_reordered_segments_fail() {
+        let key = Key::<Aes256Gcm>::from([7u8; 32]);
+        let nonce = [3u8; 12];
+        let data = vec![1u8; SEGMENT_SIZE * 2 + 5];
+
+        let mut ciphertext = Vec::new();
+        encrypt_segments(&key, &nonce, data.as_slice(), &mut ciphertext, 4).unwrap();
+        let chunk = SEGMENT_SIZE + TAG_SIZE;
+
+        let truncated = &ciphertext[..chunk * 2];
+        assert!(decrypt_segments(&key, &nonce, truncated, &mut Vec::new(), 4).is_err());
+
+        let mut swapped = ciphertext[chunk..chunk * 2].to_vec();
+        swapped.extend_from_slice(&ciphertext[..chunk]);
+        swapped.extend_from_slice(&ciphertext[chunk * 2..]);
+        assert_eq!(
+            decrypt_segments(&key, &nonce, swapped.as_slice(), &mut Vec::new(), 4).unwrap_err(),
+            DecryptError::Damaged("segment 1 failed authentication".to_string())
+        );
+
+        let wrong_key = Key::<Aes256Gcm>::from([8u8; 32]);
+        assert_eq!(
+            decrypt_first_segment(&wrong_key, &nonce, &mut ciphertext.as_slice()).unwrap_err(),
+            DecryptError::WrongPassword
+        );
+        let (chunk_read, plaintext) = decrypt_first_segment(&key, &nonce, &mut ciphertext.as_slice()).unwrap();
+        assert_eq!(chunk_read.len(), chunk);
+        assert_eq!(plaintext, &data[..SEGMENT_SIZE]);
+    }
+
+    #[test]
+    fn test_writes_in_order_up_to_the_first_failure() {
+        let data: Vec<u8> = (0..42).collect();
+        let mut output = Vec::new();
+        let result = process_segments(data.as_slice(), &mut output, 4, 3, |segment| {
+            if segment.index >= 5 {
+                return Err(format!("segment {} failed", segment.index));
+            }
+            Ok(segment.data.clone())
+        });
+        assert_eq!(result.unwrap_err(), "segment 5 failed");
+        assert_eq!(output, &data[..20]);
+
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk gone"))
+            }
+        }
+        let result = process_segments(Failing, &mut Vec::new(), 4, 2, |segment| Ok::<_, String>(segment.data.clone()));
+        assert_eq!(result.unwrap_err(), "Failed to read input: disk gone");
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::encryption::Password;
+use crate::file_ops::DecryptError;
+use crate::password::get_password;
+
+/// How often to ask again when a file does not accept any known password.
+const MAX_PROMPTS_PER_FILE: usize = 3;
+
+/// Passwords entered during one batch, by the helper question they were
+/// given for. Every file is tried with the passwords for its question before
+/// the user is asked again. Keys derived from a password stay with it until
+/// the session and its clones are dropped.
+#[derive(Clone)]
+pub struct Session {
+    show_password: bool,
+    passwords: HashMap<String, Vec<Arc<Password>>>,
+}
+
+impl Session {
+    pub fn new(show_password: bool) -> Self {
+        Self { show_password, passwords: HashMap::new() }
+    }
+
+    /// Asks for the password that goes with `helper_question`.
+    pub fn prompt(&mut self, helper_question: &str, file_count: usize) -> Result<(), String> {
+        if file_count == 1 {
+            println!("Helper question: {}", helper_question);
+        } else {
+            println!("Helper question ({} files): {}", file_count, helper_question);
+        }
+
+        let password = get_password(self.show_password)
+            .map_err(|e| format!("Failed to read password: {}", e))?;
+        self.remember(helper_question, Password::new(password));
+        Ok(())
+    }
+
+    fn remember(&mut self, helper_question: &str, password: Password) {
+        let known = self.passwords.entry(helper_question.to_string()).or_default();
+        if !known.iter().any(|known| known.as_str() == password.as_str()) {
+            known.push(Arc::new(password));
+        }
+    }
+
+    /// Runs `decrypt` with each known password for `helper_question` until
+    /// one authenticates, without asking for more. It only reads the
+    /// session, so workers can share it.
+    pub fn try_known<T, F>(&self, helper_question: &str, mut decrypt: F) -> Result<T, DecryptError>
+    where
+        F: FnMut(&Password) -> Result<T, DecryptError>,
+    {
+        for password in self.passwords.get(helper_question).into_iter().flatten() {
+            match decrypt(password) {
+                Err(DecryptError::WrongPassword) => continue,
+                result => return result,
+            }
+        }
+
+        Err(DecryptError::WrongPassword)
+    }
+
+    /// Like [`Session::try_known`], then asks for another password a few
+    /// times. An empty answer gives up on the file.
+    pub fn decrypt<T, F>(&mut self, path: &Path, helper_question: &str, mut decrypt: F) -> Result<T, DecryptError>
+    where
+        F: FnMut(&Password) -> Result<T, DecryptError>,
+    {
+        match self.try_known(helper_question, &mut decrypt) {
+            Err(DecryptError::WrongPassword) => {}
+            result => return result,
+        }
+
+        for _ in 0..MAX_PROMPTS_PER_FILE {
+            println!("The password did not work for {}. Helper question: {}", path.display(), helper_question);
+            println!("Enter another password, or leave it empty to skip this file.");
+            let password = get_password(self.show_password)
+                .map(Password::new)
+                .map_err(|e| format!("Failed to read password: {}", e))?;
+            if password.is_empty() {
+                break;
+            }
+
+            match decrypt(&password) {
+                Err(DecryptError::WrongPassword) => continue,
+                result => {
+                    if result.is_ok() {
+                        self.remember(helper_question, password);
+                    }
+                    return result;
+                }
+            }
+        }
+
+        Err(DecryptError::WrongPassword)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_ops::{decrypt_opened_folder_archive, encrypt_folder_archive, list_opened_file, DecryptOptions, EncryptOptions, EncryptedFile, Outcome};
+    use crate::segments::SEGMENT_SIZE;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_decrypt_tries_known_passwords() {
+        let mut session = Session::new(false);
+        session.remember("Pet?", Password::from("old"));
+        session.remember("Pet?", Password::from("new"));
+        session.remember("Pet?", Password::from("new"));
+
+        let mut tried = Vec::new();
+        let outcome = session.decrypt(Path::new("a.ect"), "Pet?", |password| {
+            tried.push(password.as_str().to_string());
+            if password.as_str() == "new" {
+                Ok(Outcome::Written(PathBuf::from("a")))
+            } else {
+                Err(DecryptError::WrongPassword)
+            }
+        });
+
+        assert_eq!(outcome.unwrap(), Outcome::Written(PathBuf::from("a")));
+        assert_eq!(tried, ["old", "new"]);
+
+        let error = session.decrypt(Path::new("b.ect"), "Pet?", |_| Err::<(), _>(DecryptError::Damaged("segment 2 failed authentication".to_string())));
+        assert_eq!(error.unwrap_err(), DecryptError::Damaged("segment 2 failed authentication".to_string()));
+    }
+
+    #[test]
+    fn test_damaged_later_segment_is_not_asked_again() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir(&folder).unwrap();
+        fs::write(folder.join("large.bin"), vec![7u8; SEGMENT_SIZE + 100]).unwrap();
+
+        let encrypted = encrypt_folder_archive(&folder, &Password::from("password"), "Pet?", &EncryptOptions::default()).unwrap().into_path();
+        // The archive takes two segments; break the tag of the second.
+        let mut data = fs::read(&encrypted).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&encrypted, data).unwrap();
+
+        let mut session = Session::new(false);
+        session.remember("Pet?", Password::from("password"));
+        let damaged = DecryptError::Damaged("segment 2 failed authentication".to_string());
+
+        let mut attempts = 0;
+        let mut file = EncryptedFile::open(&encrypted).unwrap();
+        let error = session.decrypt(&encrypted, "Pet?", |password| {
+            attempts += 1;
+            decrypt_opened_folder_archive(&mut file, password, &DecryptOptions::default())
+        }).unwrap_err();
+        assert_eq!(error, damaged);
+        assert_eq!(attempts, 1);
+        assert!(!folder.exists());
+        assert!(encrypted.exists());
+
+        let error = session.decrypt(&encrypted, "Pet?", |password| list_opened_file(&mut file, password, 1)).unwrap_err();
+        assert_eq!(error, damaged);
+    }
+}
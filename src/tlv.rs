@@ -0,0 +1,86 @@
+/// Set on the tag of a record that readers must understand. Records without
+/// it are optional and skipped by readers that don't know them.
+pub const CRITICAL: u16 = 0x8000;
+
+/// Bytes in front of every value: a little-endian `u16` tag and `u32` length.
+const RECORD_HEADER_LEN: usize = 6;
+
+pub fn is_critical(tag: u16) -> bool {
+    tag & CRITICAL != 0
+}
+
+/// One type-length-value record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub tag: u16,
+    pub value: &'a [u8],
+}
+
+/// Builds a sequence of records.
+#[derive(Debug, Default)]
+pub struct TlvWriter {
+    data: Vec<u8>,
+}
+
+impl TlvWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn field(&mut self, tag: u16, value: &[u8]) -> Result<&mut Self, String> {
+        let length = u32::try_from(value.len())
+            .map_err(|_| format!("Header field {:#06x} is too large", tag))?;
+        self.data.extend_from_slice(&tag.to_le_bytes());
+        self.data.extend_from_slice(&length.to_le_bytes());
+        self.data.extend_from_slice(value);
+        Ok(self)
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Splits `data` into records. Fails on a record that runs past the end;
+/// never panics.
+pub fn parse_records(mut data: &[u8]) -> Result<Vec<Record<'_>>, String> {
+    let mut records = Vec::new();
+
+    while !data.is_empty() {
+        let Some((header, rest)) = data.split_first_chunk::<RECORD_HEADER_LEN>() else {
+            return Err("Header field is truncated".to_string());
+        };
+        let tag = u16::from_le_bytes([header[0], header[1]]);
+        let length = u32::from_le_bytes([header[2], header[3], header[4], header[5]]) as usize;
+
+        let value = rest.get(..length)
+            .ok_or_else(|| format!("Header field {:#06x} is truncated", tag))?;
+        records.push(Record { tag, value });
+        data = &rest[length..];
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_round_trip() {
+        let mut writer = TlvWriter::new();
+        writer.field(1, b"question").unwrap().field(CRITICAL | 2, b"").unwrap();
+        let data = writer.finish();
+
+        let records = parse_records(&data).unwrap();
+        assert_eq!(records, [Record { tag: 1, value: b"question" }, Record { tag: CRITICAL | 2, value: b"" }]);
+        assert!(!is_critical(records[0].tag));
+        assert!(is_critical(records[1].tag));
+
+        for end in 1..data.len() {
+            if end != RECORD_HEADER_LEN + 8 {
+                assert!(parse_records(&data[..end]).is_err(), "prefix of {} bytes", end);
+            }
+        }
+    }
+}
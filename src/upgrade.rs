@@ -0,0 +1,240 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Cursor, Read};
+
+use crate::attributes::FileAttributes;
+use crate::encryption::{generate_nonce, generate_salt, Password, DEFAULT_PBKDF2_ITERATIONS};
+use crate::file_ops::{stage_encrypted_file, verify_and_commit, DecryptError, EncryptedFile, Outcome};
+use crate::metadata::{encode_file_payload, ContentType, KeyDerivation, Metadata, VERSION};
+use crate::pool::default_jobs;
+use crate::segments::encrypt_segments;
+use crate::verify::plaintext_digest;
+
+/// How `upgrade` and `rekey` rewrite encrypted files.
+#[derive(Debug, Clone)]
+pub struct UpgradeOptions {
+    /// Decrypt the rewritten file and compare it with the old one before it
+    /// replaces the old one.
+    pub verify: bool,
+    /// PBKDF2 rounds for rewritten files. Files that already use more keep
+    /// their count.
+    pub kdf_iterations: Option<u32>,
+    /// Salt of a master key shared by the files upgraded together, as in
+    /// [`crate::file_ops::EncryptOptions::master_salt`].
+    pub master_salt: Option<[u8; 32]>,
+    /// Threads used for the segments of one file.
+    pub jobs: usize,
+}
+
+impl Default for UpgradeOptions {
+    fn default() -> Self {
+        Self {
+            verify: true,
+            kdf_iterations: None,
+            master_salt: None,
+            jobs: default_jobs(),
+        }
+    }
+}
+
+impl UpgradeOptions {
+    fn kdf_iterations_for(&self, metadata: &Metadata) -> u32 {
+        self.kdf_iterations.unwrap_or(DEFAULT_PBKDF2_ITERATIONS).max(metadata.kdf_iterations)
+    }
+
+    /// Whether a file with this header is already written in the current
+    /// format with at least the requested iterations.
+    pub fn is_current(&self, metadata: &Metadata) -> bool {
+        metadata.version == VERSION && metadata.kdf_iterations == self.kdf_iterations_for(metadata)
+    }
+}
+
+/// Hashes everything read through it.
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Sha256,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// What a rewritten file is encrypted with.
+enum Secrets<'a> {
+    /// The password and helper question it already has.
+    Same,
+    /// A new password and helper question.
+    New { password: &'a Password, helper_question: &'a str },
+}
+
+/// Rewrites `file` in the current format under the same password, with a
+/// fresh salt and nonce.
+pub fn upgrade_opened_file(file: &mut EncryptedFile, password: &Password, options: &UpgradeOptions) -> Result<Outcome, DecryptError> {
+    rewrite_opened_file(file, password, Secrets::Same, options)
+}
+
+/// Rewrites `file`, opened with `password`, under `new_password` and
+/// `new_helper_question`. The file is upgraded to the current format on the
+/// way.
+pub fn rekey_opened_file(
+    file: &mut EncryptedFile,
+    password: &Password,
+    new_password: &Password,
+    new_helper_question: &str,
+    options: &UpgradeOptions,
+) -> Result<Outcome, DecryptError> {
+    let secrets = Secrets::New { password: new_password, helper_question: new_helper_question };
+    rewrite_opened_file(file, password, secrets, options)
+}
+
+/// Decrypts the old payload on one thread and pipes it straight into the
+/// encryption of the new one, so the plaintext never reaches the disk. The
+/// new file is written next to the old one and only replaces it once it is
+/// complete and, with `verify`, decrypts to the same plaintext.
+fn rewrite_opened_file(file: &mut EncryptedFile, password: &Password, secrets: Secrets, options: &UpgradeOptions) -> Result<Outcome, DecryptError> {
+    let path = file.path.clone();
+    let old = file.metadata.clone();
+    let payload = file.payload(password)?;
+
+    let (new_password, helper_question) = match secrets {
+        Secrets::Same => (password, old.helper_question.as_str()),
+        Secrets::New { password, helper_question } => (password, helper_question),
+    };
+    let (salt, key_derivation) = match options.master_salt {
+        Some(master_salt) => (master_salt, KeyDerivation::Pbkdf2Hkdf { file_salt: generate_salt() }),
+        None => (generate_salt(), KeyDerivation::Pbkdf2),
+    };
+    let kdf_iterations = options.kdf_iterations_for(&old);
+    let nonce = generate_nonce();
+    let key = new_password.file_key(&salt, &key_derivation, kdf_iterations);
+
+    let metadata = Metadata::new(nonce, salt, helper_question.to_string(), old.original_name.clone(), old.content_type.clone())
+        .with_key_derivation(key_derivation)
+        .with_kdf_iterations(kdf_iterations);
+
+    // Version 1 file payloads lack the attributes later versions start with.
+    let prefix = match old.content_type {
+        ContentType::File if old.version < 2 => encode_file_payload(&FileAttributes::default(), &[])?,
+        _ => Vec::new(),
+    };
+
+    let mut hasher = Sha256::new();
+    let staged = stage_encrypted_file(&path, &metadata, true, |output| {
+        payload.read_with(options.jobs, |reader| {
+            let plaintext = HashingReader { inner: Cursor::new(prefix).chain(reader), hasher: &mut hasher };
+            encrypt_segments(&key, &nonce, plaintext, output, options.jobs)
+        }).map_err(String::from)
+    })?;
+    let digest = <[u8; 32]>::from(hasher.finalize());
+
+    if let Ok(existing) = fs::metadata(&path) {
+        let _ = fs::set_permissions(staged.temp_path(), existing.permissions());
+    }
+    if options.verify {
+        verify_and_commit(staged, |staged_path| {
+            if plaintext_digest(staged_path, new_password, options.jobs)? != digest {
+                return Err(format!("Rewritten contents of {} do not match", path.display()));
+            }
+            Ok(())
+        })?;
+    } else {
+        staged.commit()?;
+    }
+
+    Ok(Outcome::Written(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_ops::{decrypt_file, decrypt_folder_archive, DecryptOptions};
+    use crate::metadata::{golden, MIN_VERSION};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_upgrade_every_version() {
+        let password = Password::from("golden");
+        let temp_dir = TempDir::new().unwrap();
+        let options = UpgradeOptions { master_salt: Some(generate_salt()), ..UpgradeOptions::default() };
+
+        for version in MIN_VERSION..=VERSION {
+            let dir = temp_dir.path().join(format!("v{}", version));
+            fs::create_dir(&dir).unwrap();
+
+            for name in ["hello.txt.ect", "notes.ect"] {
+                let path = dir.join(name);
+                fs::copy(golden(version, name), &path).unwrap();
+
+                let mut file = EncryptedFile::open(&path).unwrap();
+                assert_eq!(file.metadata.version, version);
+                assert_eq!(options.is_current(&file.metadata), version == VERSION);
+
+                if version == MIN_VERSION {
+                    let error = upgrade_opened_file(&mut file, &Password::from("wrong"), &options).unwrap_err();
+                    assert_eq!(error, DecryptError::WrongPassword);
+                    assert_eq!(fs::read(&path).unwrap(), fs::read(golden(version, name)).unwrap());
+                }
+
+                upgrade_opened_file(&mut file, &password, &options).unwrap();
+                let upgraded = EncryptedFile::open(&path).unwrap().metadata;
+                assert_eq!(upgraded.version, VERSION);
+                assert!(options.is_current(&upgraded));
+                assert_eq!(upgraded.helper_question, "Favourite test?");
+            }
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 2, "no temp files are left behind");
+
+            let decrypt_options = DecryptOptions::default();
+            decrypt_file(&dir.join("hello.txt.ect"), &password, &decrypt_options).unwrap();
+            decrypt_folder_archive(&dir.join("notes.ect"), &password, &decrypt_options).unwrap();
+            assert_eq!(fs::read(dir.join("hello.txt")).unwrap(), b"golden file\n");
+            assert_eq!(fs::read(dir.join("notes/sub/b.txt")).unwrap(), b"second\n");
+        }
+    }
+
+    #[test]
+    fn test_upgrade_raises_kdf_iterations() {
+        let password = Password::from("golden");
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("hello.txt.ect");
+        fs::copy(golden(VERSION, "hello.txt.ect"), &path).unwrap();
+
+        let stronger = UpgradeOptions { kdf_iterations: Some(150_000), ..UpgradeOptions::default() };
+        let mut file = EncryptedFile::open(&path).unwrap();
+        assert!(UpgradeOptions::default().is_current(&file.metadata));
+        assert!(!stronger.is_current(&file.metadata));
+
+        upgrade_opened_file(&mut file, &password, &stronger).unwrap();
+        let metadata = EncryptedFile::open(&path).unwrap().metadata;
+        assert_eq!(metadata.kdf_iterations, 150_000);
+        // Asking for the default again doesn't weaken the file.
+        assert!(UpgradeOptions::default().is_current(&metadata));
+
+        decrypt_file(&path, &password, &DecryptOptions::default()).unwrap();
+        assert_eq!(fs::read(temp_dir.path().join("hello.txt")).unwrap(), b"golden file\n");
+    }
+
+    #[test]
+    fn test_rekey_changes_password_and_question() {
+        let password = Password::from("golden");
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("notes.ect");
+        fs::copy(golden(MIN_VERSION, "notes.ect"), &path).unwrap();
+
+        let mut file = EncryptedFile::open(&path).unwrap();
+        rekey_opened_file(&mut file, &password, &Password::from("new password"), "New question?", &UpgradeOptions::default()).unwrap();
+
+        let metadata = EncryptedFile::open(&path).unwrap().metadata;
+        assert_eq!(metadata.version, VERSION);
+        assert_eq!(metadata.helper_question, "New question?");
+        assert_eq!(
+            decrypt_folder_archive(&path, &password, &DecryptOptions::default()).unwrap_err(),
+            DecryptError::WrongPassword
+        );
+        decrypt_folder_archive(&path, &Password::from("new password"), &DecryptOptions::default()).unwrap();
+        assert_eq!(fs::read(temp_dir.path().join("notes/a.txt")).unwrap(), b"first\n");
+    }
+}
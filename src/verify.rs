@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use tar::Archive;
+
+use crate::archive::{check_tar_entries, format_rejected, ArchiveOptions};
+use crate::encryption::Password;
+use crate::file_ops::{DecryptError, EncryptedFile};
+use crate::metadata::{decode_file_payload, ContentType};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ManifestEntry {
+    Directory,
+    File([u8; 32]),
+    Symlink(PathBuf),
+    Fifo,
+}
+
+/// Hashes everything `reader` yields, without holding it in memory.
+fn sha256<R: Read>(mut reader: R) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+fn authentication_error(path: &Path, error: DecryptError) -> String {
+    match error {
+        DecryptError::WrongPassword => format!("Failed to authenticate {}", path.display()),
+        error => error.to_string(),
+    }
+}
+
+/// Authenticates the whole payload of an opened encrypted file, discarding
+/// the plaintext as it streams past. The plaintext is also checked for the
+/// structure decrypting expects: the attributes in front of a file, and for
+/// folder archives a tar stream with nothing extraction would refuse.
+pub fn authenticate_opened_file(file: &mut EncryptedFile, password: &Password, jobs: usize) -> Result<(), DecryptError> {
+    let (version, content_type) = (file.metadata.version, file.metadata.content_type.clone());
+    let payload = file.payload(password)?;
+
+    match content_type {
+        ContentType::File => {
+            decode_file_payload(version, payload.head())?;
+            payload.write_to(&mut io::sink(), 0, jobs)
+        }
+        ContentType::Folder => payload.read_with(jobs, |reader| {
+            let rejected = check_tar_entries(reader)?;
+            if !rejected.is_empty() {
+                return Err(format!("Archive has entries that would not be extracted:\n  {}", format_rejected(&rejected)));
+            }
+            Ok(())
+        }),
+    }
+}
+
+/// Authenticates the whole payload of an encrypted file and hashes its
+/// plaintext as it streams past.
+pub fn plaintext_digest(path: &Path, password: &Password, jobs: usize) -> Result<[u8; 32], String> {
+    let mut file = EncryptedFile::open(path)?;
+
+    let mut hasher = Sha256::new();
+    file.payload(password)
+        .and_then(|payload| payload.write_to(&mut hasher, 0, jobs))
+        .map_err(|e| authentication_error(path, e))?;
+
+    Ok(hasher.finalize().into())
+}
+
+/// Describes the entries of a tar stream, hashing files as they stream past.
+pub fn tar_manifest<R: Read>(reader: R) -> Result<BTreeMap<PathBuf, ManifestEntry>, String> {
+    let mut manifest = BTreeMap::new();
+    let mut archive = Archive::new(reader);
+
+    for entry in archive.entries().map_err(|e| format!("Failed to read tar archive: {}", e))? {
+        let mut entry = entry.map_err(|e| format!("Failed to read tar entry: {}", e))?;
+        let path = entry.path()
+            .map_err(|e| format!("Invalid tar entry path: {}", e))?
+            .into_owned();
+        let entry_type = entry.header().entry_type();
+
+        if entry_type.is_pax_global_extensions() {
+            continue;
+        } else if entry_type.is_dir() {
+            manifest.insert(path, ManifestEntry::Directory);
+        } else if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let target = entry.link_name()
+                .map_err(|e| format!("Invalid link target for {}: {}", path.display(), e))?
+                .ok_or_else(|| format!("{} has no link target", path.display()))?
+                .into_owned();
+
+            if entry_type.is_symlink() {
+                manifest.insert(path, ManifestEntry::Symlink(target));
+            } else {
+                // A hard link shares the contents of the entry it points to.
+                let linked = match manifest.get(&target) {
+                    Some(ManifestEntry::File(hash)) => ManifestEntry::File(*hash),
+                    _ => return Err(format!("{} links to {}, which is not an earlier file", path.display(), target.display())),
+                };
+                manifest.insert(path, linked);
+            }
+        } else if entry_type.is_fifo() {
+            manifest.insert(path, ManifestEntry::Fifo);
+        } else {
+            let hash = sha256(&mut entry)
+                .map_err(|e| format!("Failed to read tar entry {}: {}", path.display(), e))?;
+            manifest.insert(path, ManifestEntry::File(hash));
+        }
+    }
+
+    Ok(manifest)
+}
+
+/// Describes a source folder the way [`crate::archive::create_tar_archive`] would archive it.
+pub fn folder_manifest(folder_path: &Path, options: &ArchiveOptions) -> Result<BTreeMap<PathBuf, ManifestEntry>, String> {
+    let mut manifest = BTreeMap::new();
+
+    let mut walker = options.filter.walker(folder_path, options.follow_symlinks)?;
+
+    for entry in walker.entries() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) if options.ignore_unreadable => continue,
+            Err(e) => return Err(format!("Failed to walk {}: {}", folder_path.display(), e)),
+        };
+        let relative_path = entry.path().strip_prefix(folder_path)
+            .map_err(|e| format!("Failed to get relative path: {}", e))?;
+        let file_type = entry.file_type();
+
+        if relative_path == Path::new("") {
+            continue;
+        }
+
+        if file_type.is_dir() {
+            manifest.insert(relative_path.to_path_buf(), ManifestEntry::Directory);
+        } else if file_type.is_file() {
+            let hash = match fs::File::open(entry.path()).and_then(sha256) {
+                Ok(hash) => hash,
+                Err(_) if options.ignore_unreadable => continue,
+                Err(e) => return Err(format!("Failed to read {}: {}", entry.path().display(), e)),
+            };
+            manifest.insert(relative_path.to_path_buf(), ManifestEntry::File(hash));
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(entry.path())
+                .map_err(|e| format!("Failed to read link {}: {}", entry.path().display(), e))?;
+            manifest.insert(relative_path.to_path_buf(), ManifestEntry::Symlink(target));
+        } else if is_stored_fifo(&entry, options) {
+            manifest.insert(relative_path.to_path_buf(), ManifestEntry::Fifo);
+        }
+    }
+
+    Ok(manifest)
+}
+
+#[cfg(unix)]
+fn is_stored_fifo(entry: &walkdir::DirEntry, options: &ArchiveOptions) -> bool {
+    use crate::archive::SpecialFilePolicy;
+    use std::os::unix::fs::FileTypeExt;
+    options.special_files == SpecialFilePolicy::Store && entry.file_type().is_fifo()
+}
+
+#[cfg(not(unix))]
+fn is_stored_fifo(_entry: &walkdir::DirEntry, _options: &ArchiveOptions) -> bool {
+    false
+}
+
+/// Checks that `encrypted_path` decrypts to exactly the bytes of
+/// `source_path`, hashing both as they stream past.
+pub fn verify_file_against_source(encrypted_path: &Path, password: &Password, source_path: &Path, jobs: usize) -> Result<(), String> {
+    let mut file = EncryptedFile::open(encrypted_path)?;
+    let version = file.metadata.version;
+    let payload = file.payload(password)
+        .map_err(|e| authentication_error(encrypted_path, e))?;
+    let (_, contents) = decode_file_payload(version, payload.head())?;
+    let attributes_len = payload.head().len() - contents.len();
+
+    let mut decrypted = Sha256::new();
+    payload.write_to(&mut decrypted, attributes_len, jobs)
+        .map_err(|e| authentication_error(encrypted_path, e))?;
+
+    let mut source = Sha256::new();
+    fs::File::open(source_path)
+        .and_then(|mut file| io::copy(&mut file, &mut source))
+        .map_err(|e| format!("Failed to read {}: {}", source_path.display(), e))?;
+
+    if decrypted.finalize() != source.finalize() {
+        return Err(format!("Decrypted contents of {} do not match {}", encrypted_path.display(), source_path.display()));
+    }
+
+    Ok(())
+}
+
+/// Checks that the archive in `encrypted_path` has the same entries and file
+/// hashes as the folder at `source_path`. The archive is decrypted straight
+/// into the manifest, so neither side is held in memory.
+pub fn verify_folder_against_source(
+    encrypted_path: &Path,
+    password: &Password,
+    source_path: &Path,
+    options: &ArchiveOptions,
+    jobs: usize,
+) -> Result<(), String> {
+    let mut file = EncryptedFile::open(encrypted_path)?;
+    let archived = file.payload(password)
+        .and_then(|payload| payload.read_with(jobs, |reader| tar_manifest(reader)))
+        .map_err(|e| authentication_error(encrypted_path, e))?;
+    let source = folder_manifest(source_path, options)?;
+
+    for (path, entry) in &source {
+        match archived.get(path) {
+            None => return Err(format!("{} is missing from the archive", path.display())),
+            Some(archived_entry) if archived_entry != entry => {
+                return Err(format!("{} differs between the archive and the source folder", path.display()));
+            }
+            Some(_) => {}
+        }
+    }
+
+    if let Some(path) = archived.keys().find(|path| !source.contains_key(*path)) {
+        return Err(format!("{} is in the archive but not in the source folder", path.display()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::create_tar_archive;
+    use crate::encryption::{generate_nonce, generate_salt, DEFAULT_PBKDF2_ITERATIONS};
+    use crate::file_ops::{encrypt_file, encrypt_folder_archive, stage_encrypted_file, EncryptOptions};
+    use crate::metadata::{golden, KeyDerivation, Metadata, VERSION};
+    use crate::segments::{encrypt_segments, SEGMENT_SIZE};
+    use tempfile::TempDir;
+
+    /// Encrypts `tar_data` as a folder archive without checking it.
+    fn write_folder_payload(path: &Path, tar_data: &[u8]) {
+        let (salt, nonce) = (generate_salt(), generate_nonce());
+        let key = Password::from("password").file_key(&salt, &KeyDerivation::Pbkdf2, DEFAULT_PBKDF2_ITERATIONS);
+        let metadata = Metadata::new(nonce, salt, "Question?".to_string(), "folder".to_string(), ContentType::Folder);
+        stage_encrypted_file(path, &metadata, false, |file| encrypt_segments(&key, &nonce, tar_data, file, 1)).unwrap().commit().unwrap();
+    }
+
+    #[test]
+    fn test_authenticate_opened_file() {
+        let password = Password::from("password");
+        for name in ["hello.txt.ect", "notes.ect"] {
+            let mut file = EncryptedFile::open(&golden(VERSION, name)).unwrap();
+            assert_eq!(authenticate_opened_file(&mut file, &Password::from("wrong"), 1).unwrap_err(), DecryptError::WrongPassword);
+            authenticate_opened_file(&mut file, &Password::from("golden"), 1).unwrap();
+        }
+
+        let temp_dir = TempDir::new().unwrap();
+
+        // A damaged segment after the first one is not a wrong password.
+        let large = temp_dir.path().join("large.bin");
+        fs::write(&large, vec![7u8; SEGMENT_SIZE + 100]).unwrap();
+        let options = EncryptOptions { keep: true, ..EncryptOptions::default() };
+        let encrypted = encrypt_file(&large, &password, "Question?", &options).unwrap().into_path();
+        let mut data = fs::read(&encrypted).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&encrypted, data).unwrap();
+        let error = authenticate_opened_file(&mut EncryptedFile::open(&encrypted).unwrap(), &password, 2).unwrap_err();
+        assert_eq!(error, DecryptError::Damaged("segment 2 failed authentication".to_string()));
+
+        // Folder archives must hold a tar stream that would be extracted.
+        let garbage = temp_dir.path().join("garbage.ect");
+        write_folder_payload(&garbage, &[b'x'; 1024]);
+        let error = authenticate_opened_file(&mut EncryptedFile::open(&garbage).unwrap(), &password, 1).unwrap_err().to_string();
+        assert!(error.contains("tar"), "{}", error);
+
+        let mut tar_data = Vec::new();
+        {
+            let mut builder = tar::Builder::new(&mut tar_data);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o4755);
+            header.set_cksum();
+            builder.append_data(&mut header, "suid", io::empty()).unwrap();
+            builder.finish().unwrap();
+        }
+        let unsafe_archive = temp_dir.path().join("unsafe.ect");
+        write_folder_payload(&unsafe_archive, &tar_data);
+        let error = authenticate_opened_file(&mut EncryptedFile::open(&unsafe_archive).unwrap(), &password, 1).unwrap_err().to_string();
+        assert!(error.contains("suid: setuid/setgid"), "{}", error);
+    }
+
+    #[test]
+    fn test_tar_manifest_matches_folder_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir_all(folder.join("subdir")).unwrap();
+        fs::write(folder.join("file1.txt"), b"File 1 content").unwrap();
+        fs::write(folder.join("subdir").join("file2.txt"), b"File 2 content").unwrap();
+
+        #[cfg(unix)]
+        {
+            fs::hard_link(folder.join("file1.txt"), folder.join("subdir").join("hard.txt")).unwrap();
+            std::os::unix::fs::symlink("../file1.txt", folder.join("subdir").join("soft.txt")).unwrap();
+        }
+
+        let options = ArchiveOptions::default();
+        let (archive_data, _) = create_tar_archive(&folder, &options).unwrap();
+
+        assert_eq!(tar_manifest(archive_data.as_slice()).unwrap(), folder_manifest(&folder, &options).unwrap());
+
+        fs::write(folder.join("file1.txt"), b"Changed").unwrap();
+        assert_ne!(tar_manifest(archive_data.as_slice()).unwrap(), folder_manifest(&folder, &options).unwrap());
+    }
+
+    #[test]
+    fn test_verify_folder_against_source() {
+        let password = Password::from("password");
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("folder");
+        fs::create_dir(&folder).unwrap();
+        fs::write(folder.join("large.bin"), vec![7u8; SEGMENT_SIZE + 100]).unwrap();
+        fs::write(folder.join("small.txt"), b"small").unwrap();
+
+        let options = EncryptOptions { keep: true, ..EncryptOptions::default() };
+        let encrypted = encrypt_folder_archive(&folder, &password, "Question?", &options).unwrap().into_path();
+        verify_folder_against_source(&encrypted, &password, &folder, &options.archive, 2).unwrap();
+
+        let error = verify_folder_against_source(&encrypted, &Password::from("wrong"), &folder, &options.archive, 2).unwrap_err();
+        assert!(error.contains("Failed to authenticate"), "{}", error);
+
+        fs::write(folder.join("large.bin"), vec![8u8; SEGMENT_SIZE + 100]).unwrap();
+        let error = verify_folder_against_source(&encrypted, &password, &folder, &options.archive, 2).unwrap_err();
+        assert!(error.contains("large.bin differs"), "{}", error);
+    }
+}
@@ -0,0 +1,46 @@
+#!/bin/sh
+# Writes testdata/golden/v<version> with an ectfy binary that writes that
+# format version:
+#
+#   testdata/golden/generate.sh <version> <path to ectfy>
+#
+# See README.md next to this script for which binary writes which version.
+set -eu
+
+if [ $# -ne 2 ]; then
+    echo "usage: $0 <version> <path to ectfy>" >&2
+    exit 2
+fi
+version=$1
+ectfy=$(cd "$(dirname "$2")" && pwd)/$(basename "$2")
+out=$(cd "$(dirname "$0")" && pwd)/v$version
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+cd "$work"
+
+# The inputs every version holds.
+printf 'golden file\n' > hello.txt
+chmod 0640 hello.txt
+mkdir -p notes/sub
+printf 'first\n' > notes/a.txt
+printf 'second\n' > notes/sub/b.txt
+
+# Password, confirmation and helper question, read as lines with -s. Each
+# input is encrypted on its own so it gets a key of its own.
+answers='golden\ngolden\nFavourite test?\n'
+for input in hello.txt notes; do
+    if [ "$version" = 1 ]; then
+        # The version 1 release took a single path and no subcommand.
+        printf "$answers" | "$ectfy" -s "$input" > /dev/null
+    else
+        printf "$answers" | ECTFY_CONFIG=/dev/null "$ectfy" -s encrypt "$input" > /dev/null
+    fi
+done
+# It also named outputs after the stem; the original name is in the header.
+if [ "$version" = 1 ]; then
+    mv hello.ect hello.txt.ect
+fi
+
+mkdir -p "$out"
+cp hello.txt.ect notes.ect "$out"/